@@ -9,6 +9,16 @@ pub trait ChunkBufferBuilder<T: Send>: Default {
 
     /// Creates a new [`ChunkBuffer`] trait instance.
     fn build(&self) -> Self::Buffer;
+
+    /// Returns the buffer size limit in bytes if buffers are limited by consumed memory.
+    fn mem_limit(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the buffer size limit in items if buffers are limited by items count.
+    fn items_limit(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// Base limited buffer interface. Provides methods for pushing data to the buffer and checking buffer state.
@@ -22,11 +32,17 @@ pub trait ChunkBuffer<T: Send>: IntoIterator<Item = T> + rayon::slice::ParallelS
     /// Returns the buffer length.
     fn len(&self) -> usize;
 
+    /// Checks if the buffer contains no elements.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Checks if the buffer reached the limit.
     fn is_full(&self) -> bool;
 }
 
 /// [`LimitedBuffer`] builder.
+#[derive(Clone)]
 pub struct LimitedBufferBuilder {
     buffer_limit: usize,
     preallocate: bool,
@@ -56,6 +72,10 @@ impl<T: Send> ChunkBufferBuilder<T> for LimitedBufferBuilder {
             LimitedBuffer::with_capacity(self.buffer_limit)
         }
     }
+
+    fn items_limit(&self) -> Option<usize> {
+        Some(self.buffer_limit)
+    }
 }
 
 impl Default for LimitedBufferBuilder {
@@ -131,6 +151,7 @@ mod test {
     use super::{ChunkBuffer, ChunkBufferBuilder, LimitedBufferBuilder};
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_limited_buffer() {
         let builder = LimitedBufferBuilder::new(2, true);
         let mut buffer = builder.build();
@@ -153,6 +174,7 @@ pub mod mem {
     use super::{ChunkBuffer, ChunkBufferBuilder};
 
     /// [`MemoryLimitedBuffer`] builder.
+    #[derive(Clone)]
     pub struct MemoryLimitedBufferBuilder {
         buffer_limit: u64,
     }
@@ -176,6 +198,10 @@ pub mod mem {
         fn build(&self) -> Self::Buffer {
             MemoryLimitedBuffer::new(self.buffer_limit)
         }
+
+        fn mem_limit(&self) -> Option<u64> {
+            Some(self.buffer_limit)
+        }
     }
 
     impl Default for MemoryLimitedBufferBuilder {
@@ -256,6 +282,7 @@ pub mod mem {
         }
 
         #[test]
+        #[allow(clippy::bool_assert_comparison)]
         fn test_memory_limited_buffer() {
             let builder = MemoryLimitedBufferBuilder::new(76);
             let mut buffer = builder.build();
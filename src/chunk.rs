@@ -6,6 +6,7 @@ use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 
 use tempfile;
 
@@ -35,7 +36,59 @@ impl<S: Error> From<io::Error> for ExternalChunkError<S> {
     }
 }
 
+/// Chunk file the items are dumped to. The file is not opened for reading until the chunk is created.
+pub struct ExternalChunkFile {
+    file: fs::File,
+    len: u64,
+    items: u64,
+    path: Option<PathBuf>,
+}
+
+impl ExternalChunkFile {
+    /// Reopens a named chunk file created by [`ExternalChunk::build_named_file`].
+    ///
+    /// # Arguments
+    /// * `path` - Path of the chunk file
+    /// * `items` - Number of items dumped to the chunk file
+    pub fn reopen(path: &Path, items: u64) -> Result<Self, io::Error> {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+
+        return Ok(ExternalChunkFile {
+            file,
+            len,
+            items,
+            path: Some(path.to_path_buf()),
+        });
+    }
+
+    /// Returns the chunk file length in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Checks if the chunk file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of items dumped to the chunk file.
+    pub fn items(&self) -> u64 {
+        self.items
+    }
+
+    /// Returns the chunk file path, `None` if the file is anonymous.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
 /// External chunk interface. Provides methods for creating a chunk stored on file system and reading data from it.
+/// With the `tracing` feature enabled the sorter records a `build_chunk` span with the number of items and bytes
+/// written around every chunk file it creates and a `merge` span around every item read while the chunks are
+/// merged, so implementations don't need to be instrumented. The spans are kept in the sorter since it is the one
+/// knowing which sorting phase a chunk is written or read in, and custom chunk formats neither have to depend on
+/// `tracing` nor to report the same fields.
 pub trait ExternalChunk<T>: Sized + Iterator<Item = Result<T, Self::DeserializationError>> {
     /// Error returned when data serialization failed.
     type SerializationError: Error;
@@ -53,26 +106,69 @@ pub trait ExternalChunk<T>: Sized + Iterator<Item = Result<T, Self::Deserializat
         items: impl IntoIterator<Item = T>,
         buf_size: Option<usize>,
     ) -> Result<Self, ExternalChunkError<Self::SerializationError>> {
+        let chunk_file = Self::build_file(dir, items, buf_size)?;
+
+        return Ok(Self::open(chunk_file, buf_size)?);
+    }
+
+    /// Creates a chunk file dumping the items to it. The file is not opened for reading,
+    /// so no read buffer is allocated until the chunk is opened.
+    ///
+    /// # Arguments
+    /// * `dir` - Directory the chunk file is created in
+    /// * `items` - Items to be dumped to the chunk
+    /// * `buf_size` - File write buffer size
+    fn build_file(
+        dir: &tempfile::TempDir,
+        items: impl IntoIterator<Item = T>,
+        buf_size: Option<usize>,
+    ) -> Result<ExternalChunkFile, ExternalChunkError<Self::SerializationError>> {
         let tmp_file = tempfile::tempfile_in(dir)?;
+        let chunk_file = write_chunk_file::<T, Self>(tmp_file, None, items, buf_size)?;
 
-        let mut chunk_writer = match buf_size {
-            Some(buf_size) => io::BufWriter::with_capacity(buf_size, tmp_file.try_clone()?),
-            None => io::BufWriter::new(tmp_file.try_clone()?),
-        };
+        return Ok(chunk_file);
+    }
 
-        Self::dump(&mut chunk_writer, items).map_err(ExternalChunkError::SerializationError)?;
+    /// Creates a named chunk file dumping the items to it. Unlike the files created by [`ExternalChunk::build_file`]
+    /// the file outlives the process, it is synced to disk and can be reopened by [`ExternalChunkFile::reopen`].
+    ///
+    /// # Arguments
+    /// * `path` - Path of the chunk file, the file must not exist
+    /// * `items` - Items to be dumped to the chunk
+    /// * `buf_size` - File write buffer size
+    fn build_named_file(
+        path: &Path,
+        items: impl IntoIterator<Item = T>,
+        buf_size: Option<usize>,
+    ) -> Result<ExternalChunkFile, ExternalChunkError<Self::SerializationError>> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        let chunk_file = write_chunk_file::<T, Self>(file, Some(path.to_path_buf()), items, buf_size)?;
+        chunk_file.file.sync_all()?;
+        // the directory entry of the new file has to be synced as well
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        sync_dir(dir.unwrap_or(Path::new(".")))?;
 
-        chunk_writer.flush()?;
+        return Ok(chunk_file);
+    }
 
+    /// Opens a chunk file for reading.
+    ///
+    /// # Arguments
+    /// * `chunk_file` - Chunk file the items were dumped to
+    /// * `buf_size` - File read buffer size
+    fn open(chunk_file: ExternalChunkFile, buf_size: Option<usize>) -> Result<Self, io::Error> {
         let mut chunk_reader = match buf_size {
-            Some(buf_size) => io::BufReader::with_capacity(buf_size, tmp_file.try_clone()?),
-            None => io::BufReader::new(tmp_file.try_clone()?),
+            Some(buf_size) => io::BufReader::with_capacity(buf_size, chunk_file.file),
+            None => io::BufReader::new(chunk_file.file),
         };
 
         chunk_reader.rewind()?;
-        let file_len = tmp_file.metadata()?.len();
 
-        return Ok(Self::new(chunk_reader.take(file_len)));
+        return Ok(Self::new(chunk_reader.take(chunk_file.len)));
     }
 
     /// Creates and instance of an external chunk.
@@ -92,6 +188,43 @@ pub trait ExternalChunk<T>: Sized + Iterator<Item = Result<T, Self::Deserializat
     ) -> Result<(), Self::SerializationError>;
 }
 
+/// Dumps the items to a chunk file counting them.
+fn write_chunk_file<T, C: ExternalChunk<T>>(
+    file: fs::File,
+    path: Option<PathBuf>,
+    items: impl IntoIterator<Item = T>,
+    buf_size: Option<usize>,
+) -> Result<ExternalChunkFile, ExternalChunkError<C::SerializationError>> {
+    let mut chunk_writer = match buf_size {
+        Some(buf_size) => io::BufWriter::with_capacity(buf_size, file.try_clone()?),
+        None => io::BufWriter::new(file.try_clone()?),
+    };
+
+    let mut count = 0;
+    let items = items.into_iter().inspect(|_| count += 1);
+    C::dump(&mut chunk_writer, items).map_err(ExternalChunkError::SerializationError)?;
+
+    chunk_writer.flush()?;
+    let len = file.metadata()?.len();
+
+    return Ok(ExternalChunkFile {
+        file,
+        len,
+        items: count,
+        path,
+    });
+}
+
+/// Syncs the directory to disk so that the files created or renamed in it are not lost on a crash.
+/// Directories can't be opened for syncing on some platforms, so nothing is done there.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    return Ok(());
+}
+
 /// RMP (Rust MessagePack) external chunk implementation.
 /// It uses MessagePack as a data serialization format.
 /// For more information see [msgpack.org](https://msgpack.org/).
@@ -159,7 +292,7 @@ where
 mod test {
     use rstest::*;
 
-    use super::{ExternalChunk, RmpExternalChunk};
+    use super::{ExternalChunk, ExternalChunkFile, RmpExternalChunk};
 
     #[fixture]
     fn tmp_dir() -> tempfile::TempDir {
@@ -177,4 +310,38 @@ mod test {
 
         assert_eq!(restored, saved);
     }
+
+    #[rstest]
+    fn test_rmp_chunk_file(tmp_dir: tempfile::TempDir) {
+        let saved = Vec::from_iter(0..100);
+
+        let chunk_file = RmpExternalChunk::build_file(&tmp_dir, saved.clone(), None).unwrap();
+        assert!(!chunk_file.is_empty());
+        assert_eq!(chunk_file.items(), 100);
+        assert!(chunk_file.path().is_none());
+
+        let chunk: RmpExternalChunk<i32> = ExternalChunk::open(chunk_file, Some(16)).unwrap();
+        let restored: Result<Vec<i32>, _> = chunk.collect();
+        let restored = restored.unwrap();
+
+        assert_eq!(restored, saved);
+    }
+
+    #[rstest]
+    fn test_rmp_chunk_named_file(tmp_dir: tempfile::TempDir) {
+        let saved = Vec::from_iter(0..100);
+        let path = tmp_dir.path().join("chunk");
+
+        let chunk_file = RmpExternalChunk::build_named_file(&path, saved.clone(), None).unwrap();
+        assert_eq!(chunk_file.path(), Some(path.as_path()));
+        drop(chunk_file);
+
+        let chunk_file = ExternalChunkFile::reopen(&path, 100).unwrap();
+        assert_eq!(chunk_file.items(), 100);
+
+        let chunk: RmpExternalChunk<i32> = ExternalChunk::open(chunk_file, None).unwrap();
+        let restored: Result<Vec<i32>, _> = chunk.collect();
+
+        assert_eq!(restored.unwrap(), saved);
+    }
 }
@@ -0,0 +1,172 @@
+//! Sorted items grouping.
+
+/// Sorted items adapter grouping consecutive items with equal keys.
+///
+/// Groups can be consumed lazily one by one using [`GroupedBy::next_group`] which streams group items
+/// right from the underlying iterator. The adapter also implements [`Iterator`] yielding groups
+/// collected into vectors, which buffers every group in memory.
+pub struct GroupedBy<T, E, I, K, KF> {
+    items: I,
+    next: Option<Result<T, E>>,
+    key: Option<K>,
+    key_fn: KF,
+}
+
+impl<T, E, I, K, KF> GroupedBy<T, E, I, K, KF>
+where
+    I: Iterator<Item = Result<T, E>>,
+    K: Clone + PartialEq,
+    KF: Fn(&T) -> K,
+{
+    /// Creates an adapter grouping the provided sorted items by key.
+    ///
+    /// # Arguments
+    /// * `items` - Items sorted by key
+    /// * `key_fn` - Function to be used to extract item key
+    pub fn new(items: I, key_fn: KF) -> Self {
+        GroupedBy {
+            items,
+            next: None,
+            key: None,
+            key_fn,
+        }
+    }
+
+    /// Returns the next group key and an iterator over the group items.
+    /// Items of the previous group that have not been consumed are skipped.
+    /// An error returned by the underlying iterator between groups is returned instead of a group,
+    /// an error inside a group is returned by the group iterator.
+    #[allow(clippy::type_complexity)]
+    pub fn next_group(&mut self) -> Option<Result<(K, Group<'_, T, E, I, K, KF>), E>> {
+        let item = loop {
+            match self.take_next()? {
+                Ok(item) if self.key.as_ref() == Some(&(self.key_fn)(&item)) => continue,
+                item => break item,
+            }
+        };
+
+        let item = match item {
+            Ok(item) => item,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let key = (self.key_fn)(&item);
+        self.key = Some(key.clone());
+        self.next = Some(Ok(item));
+
+        return Some(Ok((key, Group { grouped: self })));
+    }
+
+    fn take_next(&mut self) -> Option<Result<T, E>> {
+        self.next.take().or_else(|| self.items.next())
+    }
+}
+
+impl<T, E, I, K, KF> Iterator for GroupedBy<T, E, I, K, KF>
+where
+    I: Iterator<Item = Result<T, E>>,
+    K: Clone + PartialEq,
+    KF: Fn(&T) -> K,
+{
+    type Item = Result<(K, Vec<T>), E>;
+
+    /// Returns the next group collected into a vector.
+    fn next(&mut self) -> Option<Self::Item> {
+        return match self.next_group()? {
+            Ok((key, group)) => Some(group.collect::<Result<Vec<T>, E>>().map(|items| (key, items))),
+            Err(err) => Some(Err(err)),
+        };
+    }
+}
+
+/// Iterator over the items of a single group. It is returned by [`GroupedBy::next_group`].
+pub struct Group<'a, T, E, I, K, KF> {
+    grouped: &'a mut GroupedBy<T, E, I, K, KF>,
+}
+
+impl<'a, T, E, I, K, KF> Iterator for Group<'a, T, E, I, K, KF>
+where
+    I: Iterator<Item = Result<T, E>>,
+    K: Clone + PartialEq,
+    KF: Fn(&T) -> K,
+{
+    type Item = Result<T, E>;
+
+    /// Returns the next item of the group.
+    fn next(&mut self) -> Option<Self::Item> {
+        let grouped = &mut *self.grouped;
+
+        return match grouped.take_next()? {
+            Ok(item) if grouped.key.as_ref() != Some(&(grouped.key_fn)(&item)) => {
+                // the item belongs to the next group
+                grouped.next = Some(Ok(item));
+                None
+            }
+            item => Some(item),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::GroupedBy;
+
+    #[test]
+    #[allow(clippy::type_complexity)]
+    fn test_grouped_by() {
+        let items: Vec<Result<(char, i32), io::Error>> =
+            vec![Ok(('a', 1)), Ok(('a', 2)), Ok(('b', 3)), Ok(('c', 4)), Ok(('c', 5))];
+
+        let grouped = GroupedBy::new(items.into_iter(), |item: &(char, i32)| item.0);
+        let actual_result: Result<Vec<(char, Vec<(char, i32)>)>, _> = grouped.collect();
+
+        assert_eq!(
+            actual_result.unwrap(),
+            vec![
+                ('a', vec![('a', 1), ('a', 2)]),
+                ('b', vec![('b', 3)]),
+                ('c', vec![('c', 4), ('c', 5)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_grouped_by_lazy() {
+        let items: Vec<Result<(char, i32), io::Error>> =
+            vec![Ok(('a', 1)), Ok(('a', 2)), Ok(('b', 3)), Ok(('b', 4)), Ok(('c', 5))];
+
+        let mut grouped = GroupedBy::new(items.into_iter(), |item: &(char, i32)| item.0);
+        let mut actual_result = Vec::new();
+        while let Some(group) = grouped.next_group() {
+            let (key, mut group) = group.unwrap();
+            // only the first item of every group is consumed, the rest is skipped
+            actual_result.push((key, group.next().unwrap().unwrap().1));
+        }
+
+        assert_eq!(actual_result, vec![('a', 1), ('b', 3), ('c', 5)]);
+    }
+
+    #[test]
+    fn test_grouped_by_error() {
+        let items: Vec<Result<(char, i32), io::Error>> = vec![
+            Ok(('a', 1)),
+            Err(io::Error::other("test error")),
+            Ok(('a', 2)),
+            Ok(('b', 3)),
+        ];
+
+        let mut grouped = GroupedBy::new(items.into_iter(), |item: &(char, i32)| item.0);
+
+        let (key, group) = grouped.next_group().unwrap().unwrap();
+        let group = Vec::from_iter(group);
+        assert_eq!(key, 'a');
+        assert_eq!(group.len(), 3);
+        assert_eq!(group[1].as_ref().unwrap_err().to_string(), "test error");
+
+        let (key, group) = grouped.next().unwrap().unwrap();
+        assert_eq!((key, group), ('b', vec![('b', 3)]));
+        assert!(grouped.next().is_none());
+    }
+}
@@ -0,0 +1,95 @@
+//! Binary heap ordered by an external compare function.
+
+use std::cmp::Ordering;
+
+/// Binary min-heap. Unlike [`std::collections::BinaryHeap`] items are ordered by a compare function
+/// passed to every operation, so neither the heap nor its items need to hold the compare function.
+pub(crate) struct Heap<T> {
+    items: Vec<T>,
+}
+
+impl<T> Heap<T> {
+    /// Creates an empty heap with the specified capacity.
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Heap {
+            items: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the smallest item.
+    pub(crate) fn peek(&self) -> Option<&T> {
+        self.items.first()
+    }
+
+    /// Adds an item to the heap.
+    pub(crate) fn push(&mut self, item: T, compare: impl Fn(&T, &T) -> Ordering) {
+        self.items.push(item);
+
+        let mut idx = self.items.len() - 1;
+        while idx > 0 {
+            let parent = (idx - 1) / 2;
+            if compare(&self.items[idx], &self.items[parent]) != Ordering::Less {
+                break;
+            }
+            self.items.swap(idx, parent);
+            idx = parent;
+        }
+    }
+
+    /// Takes the smallest item out of the heap.
+    pub(crate) fn pop(&mut self, compare: impl Fn(&T, &T) -> Ordering) -> Option<T> {
+        if self.items.is_empty() {
+            return None;
+        }
+        let item = self.items.swap_remove(0);
+
+        let mut idx = 0;
+        loop {
+            let (left, right) = (2 * idx + 1, 2 * idx + 2);
+            let mut smallest = idx;
+            if left < self.items.len() && compare(&self.items[left], &self.items[smallest]) == Ordering::Less {
+                smallest = left;
+            }
+            if right < self.items.len() && compare(&self.items[right], &self.items[smallest]) == Ordering::Less {
+                smallest = right;
+            }
+            if smallest == idx {
+                break;
+            }
+            self.items.swap(idx, smallest);
+            idx = smallest;
+        }
+
+        return Some(item);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::seq::SliceRandom;
+    use rstest::*;
+
+    use super::Heap;
+
+    #[rstest]
+    #[case(0)]
+    #[case(1)]
+    #[case(100)]
+    fn test_heap(#[case] len: i32) {
+        let mut items = Vec::from_iter(0..len);
+        items.shuffle(&mut rand::thread_rng());
+
+        let mut heap = Heap::with_capacity(items.len());
+        for item in items {
+            heap.push(item, i32::cmp);
+        }
+
+        let mut actual_result = Vec::new();
+        while let Some(&smallest) = heap.peek() {
+            assert_eq!(heap.pop(i32::cmp), Some(smallest));
+            actual_result.push(smallest);
+        }
+
+        assert_eq!(actual_result, Vec::from_iter(0..len));
+    }
+}
@@ -22,10 +22,16 @@
 //! * **Memory limit support:**
 //!   memory limited sorting is supported. It allows you to limit sorting memory consumption
 //!   (`memory-limit` feature required).
+//! * **Async support:**
+//!   a `futures` stream can be sorted without blocking the `tokio` runtime
+//!   (`async` feature required).
+//! * **Tracing support:**
+//!   sorting phases are instrumented with `tracing` spans (`tracing` feature required).
 //!
 //! # Example
 //!
-//! ```no_run
+#![cfg_attr(all(feature = "memory-limit", feature = "bytesize"), doc = "```no_run")]
+#![cfg_attr(not(all(feature = "memory-limit", feature = "bytesize")), doc = "```ignore")]
 //! use std::fs;
 //! use std::io::{self, prelude::*};
 //! use std::path;
@@ -57,12 +63,36 @@
 //! }
 //! ```
 
+// the crate returns values from functions explicitly, which is its established code style
+#![allow(clippy::needless_return)]
+// `usize::is_multiple_of` is not available on the older toolchains the crate supports
+#![allow(clippy::manual_is_multiple_of)]
+
 pub mod buffer;
+pub mod cancel;
 pub mod chunk;
+pub mod group;
+mod heap;
+pub mod manifest;
 pub mod merger;
+pub mod prefetch;
+pub mod progress;
+mod selection;
 pub mod sort;
+#[cfg(feature = "async")]
+pub mod stream;
 
 pub use buffer::{ChunkBuffer, ChunkBufferBuilder, LimitedBuffer, LimitedBufferBuilder};
-pub use chunk::{ExternalChunk, RmpExternalChunk};
-pub use merger::BinaryHeapMerger;
-pub use sort::{ExternalSorter, ExternalSorterBuilder, SortError};
+pub use cancel::CancellationToken;
+pub use chunk::{ExternalChunk, ExternalChunkFile, RmpExternalChunk};
+pub use group::{Group, GroupedBy};
+pub use manifest::{Manifest, ManifestChunk};
+pub use merger::{BinaryHeapMerger, Combined, LoserTreeMerger, Merger, MergerKind};
+pub use prefetch::PrefetchedChunk;
+pub use progress::{ProgressObserver, SortStats};
+pub use sort::{
+    ExternalSorter, ExternalSorterBuilder, MergeSource, RunGeneration, SortError, SortSink, SortedByCachedKey,
+    SortedIterator,
+};
+#[cfg(feature = "async")]
+pub use stream::{AsyncExternalSorter, SortedStream};
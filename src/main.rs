@@ -23,6 +23,10 @@ fn main() {
     let threads: Option<usize> = arg_parser
         .is_present("threads")
         .then(|| arg_parser.value_of_t_or_exit("threads"));
+    let max_merge_fanin: Option<usize> = arg_parser
+        .is_present("max_merge_fanin")
+        .then(|| arg_parser.value_of_t_or_exit("max_merge_fanin"));
+    let unique = arg_parser.is_present("unique");
 
     let input = arg_parser.value_of("input").expect("value is required");
     let input_stream = match fs::File::open(input) {
@@ -47,6 +51,12 @@ fn main() {
         sorter_builder = sorter_builder.with_threads_number(threads);
     }
 
+    if let Some(max_merge_fanin) = max_merge_fanin {
+        sorter_builder = sorter_builder.with_max_merge_fanin(max_merge_fanin);
+    }
+
+    sorter_builder = sorter_builder.with_unique(unique);
+
     if let Some(tmp_dir) = tmp_dir {
         sorter_builder = sorter_builder.with_tmp_dir(path::Path::new(tmp_dir));
     }
@@ -195,6 +205,23 @@ fn build_arg_parser() -> clap::ArgMatches {
                 .help("directory to be used to store temporary data")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::new("max_merge_fanin")
+                .short('f')
+                .long("max-merge-fanin")
+                .help("maximum number of chunks to be merged at once")
+                .takes_value(true)
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(fanin) if fanin >= 2 => Ok(()),
+                    _ => Err("Merge fan-in must be an integer greater than 1".to_string()),
+                }),
+        )
+        .arg(
+            clap::Arg::new("unique")
+                .short('u')
+                .long("unique")
+                .help("output only the first of equal lines"),
+        )
         .arg(
             clap::Arg::new("chunk_size")
                 .short('c')
@@ -0,0 +1,228 @@
+//! Persistent sorting state.
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunk::{sync_dir, ExternalChunkFile};
+
+/// Name of the run manifest file in the persistent directory.
+pub const MANIFEST_FILE_NAME: &str = "manifest";
+/// Prefix of the chunk file names in the persistent directory.
+const CHUNK_FILE_PREFIX: &str = "chunk-";
+
+/// Run manifest of a persistent sorting. It is saved to the persistent directory every time a chunk is written
+/// and describes the finished chunks a restarted sorting continues with.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Identifier of the compare function the chunks are sorted with.
+    pub comparator_id: String,
+    /// Number of input items dumped to the chunks.
+    pub input_offset: u64,
+    /// If the whole input is dumped to the chunks so that only the merge is left.
+    pub input_complete: bool,
+    /// Finished chunks in the order they are merged in.
+    pub chunks: Vec<ManifestChunk>,
+    /// Index the next chunk file is named after.
+    pub next_chunk: u64,
+}
+
+/// Chunk recorded in a [`Manifest`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestChunk {
+    /// Chunk file name relative to the persistent directory.
+    pub file: PathBuf,
+    /// Number of items dumped to the chunk.
+    pub items: u64,
+    /// Merge level of the chunk.
+    pub level: usize,
+}
+
+/// Persistent directory the chunk files and the run manifest are kept in.
+pub(crate) struct Persistence {
+    dir: PathBuf,
+    comparator_id: String,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    manifest: Manifest,
+    // merged chunk files removed once the manifest stops referring to them
+    obsolete: Vec<PathBuf>,
+}
+
+impl Persistence {
+    pub(crate) fn new(dir: &Path, comparator_id: &str) -> Self {
+        Persistence {
+            dir: dir.to_path_buf(),
+            comparator_id: comparator_id.to_string(),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Reads the manifest saved to the persistent directory.
+    pub(crate) fn read_manifest(&self) -> io::Result<Option<Manifest>> {
+        let data = match fs::read(self.dir.join(MANIFEST_FILE_NAME)) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let manifest = rmp_serde::from_slice(&data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        return Ok(Some(manifest));
+    }
+
+    /// Loads the saved manifest reopening the chunk files it refers to. Chunk files written after the manifest
+    /// was saved are removed. An empty manifest is returned if no sorting was persisted before.
+    pub(crate) fn resume(&self) -> io::Result<(Vec<(usize, ExternalChunkFile)>, Manifest)> {
+        fs::create_dir_all(&self.dir)?;
+
+        let manifest = match self.read_manifest()? {
+            Some(manifest) if manifest.comparator_id != self.comparator_id => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "persisted chunks are sorted by comparator '{}', not '{}'",
+                        manifest.comparator_id, self.comparator_id
+                    ),
+                ));
+            }
+            Some(manifest) => manifest,
+            None => Manifest {
+                comparator_id: self.comparator_id.clone(),
+                ..Manifest::default()
+            },
+        };
+
+        self.remove_chunk_files(|file| !manifest.chunks.iter().any(|chunk| chunk.file == file))?;
+
+        let mut chunks = Vec::with_capacity(manifest.chunks.len());
+        for chunk in &manifest.chunks {
+            let chunk_file = ExternalChunkFile::reopen(&self.dir.join(&chunk.file), chunk.items)?;
+            chunks.push((chunk.level, chunk_file));
+        }
+
+        *self.lock() = State {
+            manifest: manifest.clone(),
+            obsolete: Vec::new(),
+        };
+
+        return Ok((chunks, manifest));
+    }
+
+    /// Returns the path of a new chunk file.
+    pub(crate) fn next_chunk_path(&self) -> PathBuf {
+        let mut state = self.lock();
+        let index = state.manifest.next_chunk;
+        state.manifest.next_chunk += 1;
+
+        return self.dir.join(format!("{}{:08}", CHUNK_FILE_PREFIX, index));
+    }
+
+    /// Marks a merged chunk file to be removed once the next manifest is saved.
+    pub(crate) fn retire(&self, path: &Path) {
+        self.lock().obsolete.push(path.to_path_buf());
+    }
+
+    /// Saves the manifest replacing the previous one atomically and removes the chunk files merged since then.
+    pub(crate) fn save(
+        &self,
+        chunks: &[(usize, ExternalChunkFile)],
+        input_offset: u64,
+        input_complete: bool,
+    ) -> io::Result<()> {
+        let mut state = self.lock();
+
+        state.manifest.input_offset = input_offset;
+        state.manifest.input_complete = input_complete;
+        state.manifest.chunks = chunks
+            .iter()
+            .map(|(level, chunk_file)| ManifestChunk {
+                file: chunk_file
+                    .path()
+                    .and_then(Path::file_name)
+                    .map(PathBuf::from)
+                    .unwrap_or_default(),
+                items: chunk_file.items(),
+                level: *level,
+            })
+            .collect();
+
+        let data = rmp_serde::to_vec(&state.manifest).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+        let mut tmp_file = fs::File::create(&tmp_path)?;
+        tmp_file.write_all(&data)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(MANIFEST_FILE_NAME))?;
+        sync_dir(&self.dir)?;
+
+        for path in state.obsolete.drain(..) {
+            remove_file(&path)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Removes the chunk files written since the manifest was saved last, the merged chunk files it refers to
+    /// are kept as well.
+    pub(crate) fn discard_unsaved(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        state.obsolete.clear();
+
+        let chunks = self
+            .read_manifest()?
+            .map(|manifest| manifest.chunks)
+            .unwrap_or_default();
+        return self.remove_chunk_files(|file| !chunks.iter().any(|chunk| chunk.file == file));
+    }
+
+    /// Removes the manifest and all the chunk files from the persistent directory.
+    pub(crate) fn clear(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        state.obsolete.clear();
+
+        remove_file(&self.dir.join(MANIFEST_FILE_NAME))?;
+        self.remove_chunk_files(|_| true)?;
+        state.manifest = Manifest::default();
+
+        return Ok(());
+    }
+
+    /// Removes the chunk files whose names are matched by the predicate.
+    fn remove_chunk_files(&self, predicate: impl Fn(&Path) -> bool) -> io::Result<()> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        for entry in entries {
+            let file_name = PathBuf::from(entry?.file_name());
+            let is_chunk = file_name
+                .to_str()
+                .is_some_and(|name| name.starts_with(CHUNK_FILE_PREFIX));
+            if is_chunk && predicate(&file_name) {
+                remove_file(&self.dir.join(file_name))?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Removes a file ignoring it if it does not exist.
+fn remove_file(path: &Path) -> io::Result<()> {
+    return match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    };
+}
@@ -1,83 +1,194 @@
-//! Binary heap merger.
+//! Sorted inputs mergers.
 
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
 use std::error::Error;
 
-/// Value wrapper binding custom compare function to a value.
-struct OrderedWrapper<T, F>
+use crate::cancel::CancellationToken;
+use crate::heap::Heap;
+
+/// Binary heap merger implementation.
+/// Merges multiple sorted inputs into a single sorted output.
+/// Time complexity is *m* \* log(*n*) in worst case where *m* is the number of items,
+/// *n* is the number of chunks (inputs).
+pub struct BinaryHeapMerger<T, E, F, C>
 where
+    E: Error,
     F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
 {
-    value: T,
+    // current items of the inputs paired with the input indices
+    items: Heap<(T, usize)>,
+    chunks: Vec<C::IntoIter>,
+    initiated: bool,
+    stable: bool,
+    cancellation: Option<CancellationToken>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
     compare: F,
 }
 
-impl<T, F> OrderedWrapper<T, F>
+impl<T, E, F, C> BinaryHeapMerger<T, E, F, C>
 where
+    E: Error,
     F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
 {
-    fn wrap(value: T, compare: F) -> Self {
-        OrderedWrapper { value, compare }
+    /// Creates an instance of a binary heap merger using chunks as inputs.
+    /// Chunk items should be sorted in ascending order otherwise the result is undefined.
+    ///
+    /// # Arguments
+    /// * `chunks` - Chunks to be merged in a single sorted one
+    pub fn new<I>(chunks: I, compare: F) -> Self
+    where
+        I: IntoIterator<Item = C>,
+    {
+        let chunks = Vec::from_iter(chunks.into_iter().map(|c| c.into_iter()));
+        let items = Heap::with_capacity(chunks.len());
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("merge", merger = "binary_heap", chunks = chunks.len());
+
+        return BinaryHeapMerger {
+            chunks,
+            items,
+            compare,
+            initiated: false,
+            stable: true,
+            cancellation: None,
+            #[cfg(feature = "tracing")]
+            span,
+        };
     }
 
-    fn unwrap(self) -> T {
-        self.value
+    /// Sets if items considered equal are returned in the order of the inputs they are taken from
+    /// which is the default. An unstable merge skips input index comparison of equal items.
+    pub fn with_stability(mut self, stable: bool) -> Self {
+        self.stable = stable;
+        return self;
     }
-}
 
-impl<T, F> PartialEq for OrderedWrapper<T, F>
-where
-    F: Fn(&T, &T) -> Ordering,
-{
-    fn eq(&self, other: &Self) -> bool {
-        self.cmp(other) == Ordering::Equal
+    /// Sets a token cancelling the merge. Once the token is cancelled no more items are returned.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        return self;
     }
-}
 
-impl<T, F> Eq for OrderedWrapper<T, F> where F: Fn(&T, &T) -> Ordering {}
+    /// Returns `true` if the merge has been cancelled.
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
 
-impl<T, F> PartialOrd for OrderedWrapper<T, F>
+    /// Adds an input item to the heap.
+    fn push(&mut self, item: T, idx: usize) {
+        let (compare, stable) = (&self.compare, self.stable);
+        self.items.push((item, idx), |a, b| match compare(&a.0, &b.0) {
+            Ordering::Equal if stable => a.1.cmp(&b.1),
+            ordering => ordering,
+        });
+    }
+
+    /// Takes the smallest item out of the heap.
+    fn pop(&mut self) -> Option<(T, usize)> {
+        let (compare, stable) = (&self.compare, self.stable);
+        self.items.pop(|a, b| match compare(&a.0, &b.0) {
+            Ordering::Equal if stable => a.1.cmp(&b.1),
+            ordering => ordering,
+        })
+    }
+}
+
+impl<T, E, F, C> Iterator for BinaryHeapMerger<T, E, F, C>
 where
+    E: Error,
     F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
 {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    type Item = Result<T, E>;
+
+    /// Returns the next item from the inputs in ascending order.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_cancelled() {
+            return None;
+        }
+
+        let item = {
+            // the chunk items are read and compared within the merge span
+            #[cfg(feature = "tracing")]
+            let _span = self.span.clone().entered();
+            self.merge_next()
+        };
+        // the merge span lasts till the inputs are exhausted
+        #[cfg(feature = "tracing")]
+        if item.is_none() {
+            self.span = tracing::Span::none();
+        }
+
+        return item;
     }
 }
-impl<T, F> Ord for OrderedWrapper<T, F>
+
+impl<T, E, F, C> BinaryHeapMerger<T, E, F, C>
 where
+    E: Error,
     F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
 {
-    fn cmp(&self, other: &Self) -> Ordering {
-        (self.compare)(&self.value, &other.value)
+    fn merge_next(&mut self) -> Option<Result<T, E>> {
+        if !self.initiated {
+            for idx in 0..self.chunks.len() {
+                if let Some(item) = self.chunks[idx].next() {
+                    match item {
+                        Ok(item) => self.push(item, idx),
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+            }
+            self.initiated = true;
+        }
+
+        let (result, idx) = self.pop()?;
+        if let Some(item) = self.chunks[idx].next() {
+            match item {
+                Ok(item) => self.push(item, idx),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        return Some(Ok(result));
     }
 }
 
-/// Binary heap merger implementation.
+/// Loser tree (tournament tree) merger implementation.
 /// Merges multiple sorted inputs into a single sorted output.
-/// Time complexity is *m* \* log(*n*) in worst case where *m* is the number of items,
-/// *n* is the number of chunks (inputs).
-pub struct BinaryHeapMerger<T, E, F, C>
+/// Unlike [`BinaryHeapMerger`] it makes only log(*n*) comparisons per item since a new item
+/// is compared only with the losers stored on the path from its input to the tree root.
+/// Items considered equal are returned in the order of the inputs they are taken from.
+pub struct LoserTreeMerger<T, E, F, C>
 where
     E: Error,
     F: Fn(&T, &T) -> Ordering,
     C: IntoIterator<Item = Result<T, E>>,
 {
-    // binary heap is max-heap by default so we reverse it to convert it to min-heap
-    items: BinaryHeap<(std::cmp::Reverse<OrderedWrapper<T, F>>, std::cmp::Reverse<usize>)>,
+    // the first node holds the current winner, the rest of the nodes hold the losers of the matches,
+    // node `i` children are nodes `2i` and `2i + 1`, input `j` is a leaf node `n + j`
+    tree: Vec<usize>,
+    // current items of the inputs, exhausted inputs hold nothing
+    heads: Vec<Option<T>>,
     chunks: Vec<C::IntoIter>,
     initiated: bool,
+    stable: bool,
+    cancellation: Option<CancellationToken>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
     compare: F,
 }
 
-impl<T, E, F, C> BinaryHeapMerger<T, E, F, C>
+impl<T, E, F, C> LoserTreeMerger<T, E, F, C>
 where
     E: Error,
     F: Fn(&T, &T) -> Ordering,
     C: IntoIterator<Item = Result<T, E>>,
 {
-    /// Creates an instance of a binary heap merger using chunks as inputs.
+    /// Creates an instance of a loser tree merger using chunks as inputs.
     /// Chunk items should be sorted in ascending order otherwise the result is undefined.
     ///
     /// # Arguments
@@ -87,52 +198,329 @@ where
         I: IntoIterator<Item = C>,
     {
         let chunks = Vec::from_iter(chunks.into_iter().map(|c| c.into_iter()));
-        let items = BinaryHeap::with_capacity(chunks.len());
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("merge", merger = "loser_tree", chunks = chunks.len());
 
-        return BinaryHeapMerger {
+        return LoserTreeMerger {
+            tree: Vec::with_capacity(chunks.len()),
+            heads: Vec::with_capacity(chunks.len()),
             chunks,
-            items,
             compare,
             initiated: false,
+            stable: true,
+            cancellation: None,
+            #[cfg(feature = "tracing")]
+            span,
         };
     }
+
+    /// Sets if items considered equal are returned in the order of the inputs they are taken from
+    /// which is the default. An unstable merge skips input index comparison of equal items.
+    pub fn with_stability(mut self, stable: bool) -> Self {
+        self.stable = stable;
+        return self;
+    }
+
+    /// Sets a token cancelling the merge. Once the token is cancelled no more items are returned.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        return self;
+    }
+
+    /// Returns `true` if the merge has been cancelled.
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Fetches the first item of every input and plays the initial tournament.
+    /// Returns the first error occurred, the failed inputs are considered exhausted.
+    fn init(&mut self) -> Option<E> {
+        let mut error = None;
+        for chunk in self.chunks.iter_mut() {
+            let head = match chunk.next() {
+                Some(Ok(item)) => Some(item),
+                Some(Err(err)) => {
+                    error.get_or_insert(err);
+                    None
+                }
+                None => None,
+            };
+            self.heads.push(head);
+        }
+
+        let n = self.heads.len();
+        if n == 0 {
+            return error;
+        }
+
+        let mut winners = vec![0; 2 * n];
+        for (idx, winner) in winners[n..].iter_mut().enumerate() {
+            *winner = idx;
+        }
+
+        self.tree.resize(n, 0);
+        for node in (1..n).rev() {
+            let (left, right) = (winners[2 * node], winners[2 * node + 1]);
+            let (winner, loser) = if self.beats(left, right) {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            winners[node] = winner;
+            self.tree[node] = loser;
+        }
+        self.tree[0] = winners[1];
+
+        return error;
+    }
+
+    /// Replays the matches on the path from the input leaf to the root after the input head has changed.
+    fn replay(&mut self, idx: usize) {
+        let mut winner = idx;
+        let mut node = (idx + self.heads.len()) / 2;
+
+        while node > 0 {
+            let loser = self.tree[node];
+            if self.beats(loser, winner) {
+                self.tree[node] = winner;
+                winner = loser;
+            }
+            node /= 2;
+        }
+        self.tree[0] = winner;
+    }
+
+    /// Checks if the head of the input `a` precedes the head of the input `b`.
+    /// Exhausted inputs lose to any other one, ties are broken by the input index if the merge is stable
+    /// otherwise the current winner keeps its place.
+    fn beats(&self, a: usize, b: usize) -> bool {
+        match (&self.heads[a], &self.heads[b]) {
+            (Some(a_item), Some(b_item)) => match (self.compare)(a_item, b_item) {
+                Ordering::Less => true,
+                Ordering::Greater => false,
+                Ordering::Equal => self.stable && a < b,
+            },
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => a < b,
+        }
+    }
 }
 
-impl<T, E, F, C> Iterator for BinaryHeapMerger<T, E, F, C>
+impl<T, E, F, C> Iterator for LoserTreeMerger<T, E, F, C>
 where
     E: Error,
-    F: Fn(&T, &T) -> Ordering + Copy,
+    F: Fn(&T, &T) -> Ordering,
     C: IntoIterator<Item = Result<T, E>>,
 {
     type Item = Result<T, E>;
 
     /// Returns the next item from the inputs in ascending order.
     fn next(&mut self) -> Option<Self::Item> {
+        if self.is_cancelled() {
+            return None;
+        }
+
+        let item = {
+            // the chunk items are read and compared within the merge span
+            #[cfg(feature = "tracing")]
+            let _span = self.span.clone().entered();
+            self.merge_next()
+        };
+        // the merge span lasts till the inputs are exhausted
+        #[cfg(feature = "tracing")]
+        if item.is_none() {
+            self.span = tracing::Span::none();
+        }
+
+        return item;
+    }
+}
+
+impl<T, E, F, C> LoserTreeMerger<T, E, F, C>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
+{
+    fn merge_next(&mut self) -> Option<Result<T, E>> {
         if !self.initiated {
-            for (idx, chunk) in self.chunks.iter_mut().enumerate() {
-                if let Some(item) = chunk.next() {
-                    match item {
-                        Ok(item) => self
-                            .items
-                            .push((std::cmp::Reverse(OrderedWrapper::wrap(item, self.compare)), std::cmp::Reverse(idx))),
-                        Err(err) => return Some(Err(err)),
-                    }
-                }
-            }
             self.initiated = true;
+            if let Some(err) = self.init() {
+                return Some(Err(err));
+            }
         }
 
-        let (result, idx) = self.items.pop()?;
-        if let Some(item) = self.chunks[idx.0].next() {
-            match item {
-                Ok(item) => self
-                    .items
-                    .push((std::cmp::Reverse(OrderedWrapper::wrap(item, self.compare)), idx)),
-                Err(err) => return Some(Err(err)),
+        let idx = *self.tree.first()?;
+        let result = self.heads[idx].take()?;
+
+        let error = match self.chunks[idx].next() {
+            Some(Ok(item)) => {
+                self.heads[idx] = Some(item);
+                None
             }
+            Some(Err(err)) => Some(err),
+            None => None,
+        };
+        self.replay(idx);
+
+        return match error {
+            Some(err) => Some(Err(err)),
+            None => Some(Ok(result)),
+        };
+    }
+}
+
+/// Merger implementation to be used to merge sorted chunks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergerKind {
+    /// [`BinaryHeapMerger`] is used.
+    #[default]
+    BinaryHeap,
+    /// [`LoserTreeMerger`] is used.
+    LoserTree,
+}
+
+/// Merger which implementation is selected at run time.
+pub enum Merger<T, E, F, C>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
+{
+    /// Binary heap merger.
+    BinaryHeap(BinaryHeapMerger<T, E, F, C>),
+    /// Loser tree merger.
+    LoserTree(LoserTreeMerger<T, E, F, C>),
+}
+
+impl<T, E, F, C> Merger<T, E, F, C>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
+{
+    /// Creates an instance of a merger of the provided kind using chunks as inputs.
+    /// Chunk items should be sorted in ascending order otherwise the result is undefined.
+    ///
+    /// # Arguments
+    /// * `kind` - Merger implementation
+    /// * `chunks` - Chunks to be merged in a single sorted one
+    pub fn new<I>(kind: MergerKind, chunks: I, compare: F) -> Self
+    where
+        I: IntoIterator<Item = C>,
+    {
+        match kind {
+            MergerKind::BinaryHeap => Merger::BinaryHeap(BinaryHeapMerger::new(chunks, compare)),
+            MergerKind::LoserTree => Merger::LoserTree(LoserTreeMerger::new(chunks, compare)),
+        }
+    }
+
+    /// Returns the compare function the items are merged by.
+    fn compare(&self) -> &F {
+        match self {
+            Merger::BinaryHeap(merger) => &merger.compare,
+            Merger::LoserTree(merger) => &merger.compare,
+        }
+    }
+
+    /// Sets if items considered equal are returned in the order of the inputs they are taken from
+    /// which is the default.
+    pub fn with_stability(self, stable: bool) -> Self {
+        match self {
+            Merger::BinaryHeap(merger) => Merger::BinaryHeap(merger.with_stability(stable)),
+            Merger::LoserTree(merger) => Merger::LoserTree(merger.with_stability(stable)),
+        }
+    }
+
+    /// Sets a token cancelling the merge. Once the token is cancelled no more items are returned.
+    pub fn with_cancellation(self, token: CancellationToken) -> Self {
+        match self {
+            Merger::BinaryHeap(merger) => Merger::BinaryHeap(merger.with_cancellation(token)),
+            Merger::LoserTree(merger) => Merger::LoserTree(merger.with_cancellation(token)),
+        }
+    }
+}
+
+impl<T, E, F, C> Iterator for Merger<T, E, F, C>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
+{
+    type Item = Result<T, E>;
+
+    /// Returns the next item from the inputs in ascending order.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Merger::BinaryHeap(merger) => merger.next(),
+            Merger::LoserTree(merger) => merger.next(),
         }
+    }
+}
 
-        return Some(Ok(result.0.unwrap()));
+/// Merger adapter combining consecutive equal items into a single one.
+/// Equal items are combined in the order they are emitted which preserves sorting stability.
+/// Items are compared by the merger compare function.
+pub struct Combined<T, E, F, C, R>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
+{
+    merger: Merger<T, E, F, C>,
+    next: Option<Result<T, E>>,
+    combine: R,
+}
+
+impl<T, E, F, C, R> Combined<T, E, F, C, R>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
+    R: Fn(T, T) -> T,
+{
+    /// Creates an adapter combining equal items of the merger output.
+    ///
+    /// # Arguments
+    /// * `merger` - Merger to take the items from
+    /// * `combine` - Function to be used to combine the accumulated item with the next equal one
+    pub fn new(merger: Merger<T, E, F, C>, combine: R) -> Self {
+        Combined {
+            merger,
+            next: None,
+            combine,
+        }
+    }
+}
+
+impl<T, E, F, C, R> Iterator for Combined<T, E, F, C, R>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: IntoIterator<Item = Result<T, E>>,
+    R: Fn(T, T) -> T,
+{
+    type Item = Result<T, E>;
+
+    /// Returns the next item combined with all the following equal ones.
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut item = match self.next.take().or_else(|| self.merger.next())? {
+            Ok(item) => item,
+            Err(err) => return Some(Err(err)),
+        };
+
+        loop {
+            match self.merger.next() {
+                Some(Ok(next)) if (self.merger.compare())(&item, &next) == Ordering::Equal => {
+                    item = (self.combine)(item, next);
+                }
+                next => {
+                    self.next = next;
+                    return Some(Ok(item));
+                }
+            }
+        }
     }
 }
 
@@ -142,7 +530,7 @@ mod test {
     use std::error::Error;
     use std::io::{self, ErrorKind};
 
-    use super::BinaryHeapMerger;
+    use super::{CancellationToken, Combined, Merger, MergerKind};
 
     #[rstest]
     #[case(
@@ -184,11 +572,14 @@ mod test {
             Result::Err(io::Error::new(ErrorKind::Other, "test error")),
         ],
     )]
+    #[allow(clippy::io_other_error)]
     fn test_merger(
         #[case] chunks: Vec<Vec<Result<i32, io::Error>>>,
         #[case] expected_result: Vec<Result<i32, io::Error>>,
+        #[values(MergerKind::BinaryHeap, MergerKind::LoserTree)] kind: MergerKind,
+        #[values(true, false)] stable: bool,
     ) {
-        let merger = BinaryHeapMerger::new(chunks, i32::cmp);
+        let merger = Merger::new(kind, chunks, i32::cmp).with_stability(stable);
         let actual_result = merger.collect();
         assert!(
             compare_vectors_of_result::<_, io::Error>(&actual_result, &expected_result),
@@ -198,6 +589,61 @@ mod test {
         );
     }
 
+    #[rstest]
+    fn test_merger_stability(
+        #[values(1, 2, 5, 8)] chunks_number: usize,
+        #[values(MergerKind::BinaryHeap, MergerKind::LoserTree)] kind: MergerKind,
+    ) {
+        // every chunk contains items (key, chunk index) sorted by key
+        let chunks = Vec::from_iter((0..chunks_number).map(|idx| {
+            Vec::from_iter(
+                (0..20)
+                    .filter(|key| key % (idx + 1) == 0)
+                    .map(|key| Ok::<_, io::Error>((key, idx))),
+            )
+        }));
+
+        let merger = Merger::new(kind, chunks, |a: &(usize, usize), b: &(usize, usize)| a.0.cmp(&b.0));
+        let actual_result: Result<Vec<(usize, usize)>, _> = merger.collect();
+
+        let mut expected_result = Vec::from_iter((0..chunks_number).flat_map(|idx| {
+            (0..20)
+                .filter(move |key| key % (idx + 1) == 0)
+                .map(move |key| (key, idx))
+        }));
+        expected_result.sort();
+
+        assert_eq!(actual_result.unwrap(), expected_result);
+    }
+
+    #[rstest]
+    fn test_combined_merger(#[values(MergerKind::BinaryHeap, MergerKind::LoserTree)] kind: MergerKind) {
+        let chunks: Vec<Vec<Result<(char, i32), io::Error>>> = vec![
+            vec![Ok(('a', 1)), Ok(('a', 2)), Ok(('c', 3)), Ok(('e', 4))],
+            vec![Ok(('a', 10)), Ok(('b', 20)), Ok(('c', 30)), Ok(('c', 40))],
+        ];
+
+        let merger = Merger::new(kind, chunks, |a: &(char, i32), b: &(char, i32)| a.0.cmp(&b.0));
+        let combined = Combined::new(merger, |a: (char, i32), b: (char, i32)| (a.0, a.1 + b.1));
+        let actual_result: Result<Vec<(char, i32)>, _> = combined.collect();
+
+        assert_eq!(actual_result.unwrap(), vec![('a', 13), ('b', 20), ('c', 73), ('e', 4)]);
+    }
+
+    #[rstest]
+    fn test_merger_cancellation(#[values(MergerKind::BinaryHeap, MergerKind::LoserTree)] kind: MergerKind) {
+        let chunks: Vec<Vec<Result<i32, io::Error>>> = vec![vec![Ok(1), Ok(3), Ok(5)], vec![Ok(2), Ok(4)]];
+
+        let token = CancellationToken::new();
+        let mut merger = Merger::new(kind, chunks, i32::cmp).with_cancellation(token.clone());
+
+        assert_eq!(merger.next().unwrap().unwrap(), 1);
+        assert_eq!(merger.next().unwrap().unwrap(), 2);
+        token.cancel();
+        assert!(merger.next().is_none());
+    }
+
+    #[allow(clippy::ptr_arg, clippy::into_iter_on_ref)]
     fn compare_vectors_of_result<T: PartialEq, E: Error + 'static>(
         actual: &Vec<Result<T, E>>,
         expected: &Vec<Result<T, E>>,
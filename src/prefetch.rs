@@ -0,0 +1,287 @@
+//! Prefetched chunk.
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::vec;
+
+/// Batch read by a prefetching job or the panic raised while it was read.
+type Batch<T, E> = Result<Vec<Result<T, E>>, Box<dyn Any + Send>>;
+
+/// Chunk reader prefetching items on a thread pool.
+/// Items are read from the underlying chunk in batches by a pool job which re-spawns itself for the next batch
+/// while fewer than `queue_size` batches are waiting for the consumer, so the consumer does not block on I/O
+/// and deserialization unless they are not ready yet. At most one job reads a chunk at a time and a chunk takes
+/// a pool thread only while a batch is being read, so any number of chunks can be prefetched by a pool
+/// of a few threads without any of them waiting for another one.
+pub struct PrefetchedChunk<T, E> {
+    receiver: mpsc::Receiver<Batch<T, E>>,
+    batch: vec::IntoIter<Result<T, E>>,
+    batch_size: usize,
+    // takes a received batch into account resuming the reading if it has been suspended
+    on_received: Box<dyn Fn(bool) + Send>,
+    finished: bool,
+}
+
+/// Prefetching state shared by the consumer and the reading job.
+struct Reader<I, T, E> {
+    thread_pool: Arc<rayon::ThreadPool>,
+    batch_size: usize,
+    queue_size: usize,
+    schedule: Mutex<Schedule<I, T, E>>,
+}
+
+/// The lock is held only while a job is scheduled, never while a batch is being read.
+struct Schedule<I, T, E> {
+    // batches sent to the consumer and not received yet
+    queued: usize,
+    // the chunk and the batch sender kept while the reading is suspended, otherwise they are owned by the job
+    suspended: Option<(I, mpsc::Sender<Batch<T, E>>)>,
+}
+
+impl<T, E> PrefetchedChunk<T, E>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    /// Creates a prefetched chunk reading the provided chunk on the thread pool.
+    ///
+    /// # Arguments
+    /// * `chunk` - Chunk to be read
+    /// * `thread_pool` - Thread pool the chunk is read on
+    /// * `batch_size` - Number of items read at once
+    /// * `queue_size` - Maximum number of batches read in advance
+    pub fn new<C>(chunk: C, thread_pool: Arc<rayon::ThreadPool>, batch_size: usize, queue_size: usize) -> Self
+    where
+        C: IntoIterator<Item = Result<T, E>>,
+        C::IntoIter: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let reader = Arc::new(Reader {
+            thread_pool,
+            batch_size,
+            queue_size,
+            schedule: Mutex::new(Schedule {
+                queued: 0,
+                suspended: None,
+            }),
+        });
+        Reader::spawn(&reader, chunk.into_iter(), sender);
+
+        let on_received = Box::new(move |received: bool| {
+            let mut schedule = reader.lock();
+            if received {
+                schedule.queued -= 1;
+            }
+            // the reading is resumed once the queue is not full or, without batches read in advance,
+            // once the next batch is needed
+            let limit = if received { reader.queue_size } else { 1 };
+            if schedule.queued < limit {
+                if let Some((chunk, sender)) = schedule.suspended.take() {
+                    drop(schedule);
+                    Reader::spawn(&reader, chunk, sender);
+                }
+            }
+        });
+
+        return PrefetchedChunk {
+            receiver,
+            batch: Vec::new().into_iter(),
+            batch_size,
+            on_received,
+            finished: false,
+        };
+    }
+}
+
+impl<I, T, E> Reader<I, T, E>
+where
+    I: Iterator<Item = Result<T, E>> + Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawns a job reading the next batch of the chunk. The job re-spawns itself for the following batch
+    /// unless the queue is full, in which case the reading is suspended till the consumer takes a batch.
+    fn spawn(reader: &Arc<Self>, chunk: I, sender: mpsc::Sender<Batch<T, E>>) {
+        let job_reader = Arc::clone(reader);
+        reader.thread_pool.spawn(move || {
+            let reader = job_reader;
+            let mut chunk = Some(chunk);
+            let batch = panic::catch_unwind(AssertUnwindSafe(|| read_batch(&mut chunk, reader.batch_size)));
+            if batch.is_err() {
+                chunk = None;
+            }
+
+            let mut schedule = reader.lock();
+            schedule.queued += 1;
+            // the receiver is gone if the consumer has been dropped
+            if sender.send(batch).is_err() {
+                return;
+            }
+            // the chunk is dropped once it is exhausted or broken
+            if let Some(chunk) = chunk {
+                if schedule.queued < reader.queue_size {
+                    drop(schedule);
+                    Reader::spawn(&reader, chunk, sender);
+                } else {
+                    schedule.suspended = Some((chunk, sender));
+                }
+            }
+        });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Schedule<I, T, E>> {
+        self.schedule.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T, E> Iterator for PrefetchedChunk<T, E> {
+    type Item = Result<T, E>;
+
+    /// Returns the next item of the chunk. A panic raised while the chunk was read is resumed here.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.batch.next() {
+                return Some(item);
+            }
+            if self.finished {
+                return None;
+            }
+
+            (self.on_received)(false);
+            let batch = self.receiver.recv().ok()?;
+            (self.on_received)(true);
+            let batch = match batch {
+                Ok(batch) => batch,
+                Err(payload) => {
+                    self.finished = true;
+                    panic::resume_unwind(payload);
+                }
+            };
+            // a short batch is the last one, the chunk is either exhausted or broken
+            if batch.len() < self.batch_size {
+                self.finished = true;
+            }
+            self.batch = batch.into_iter();
+        }
+    }
+}
+
+/// Reads the next batch from the chunk. The chunk is dropped once it is exhausted or broken.
+fn read_batch<T, E>(chunk: &mut Option<impl Iterator<Item = Result<T, E>>>, batch_size: usize) -> Vec<Result<T, E>> {
+    let mut batch = Vec::new();
+
+    if let Some(items) = chunk {
+        for item in items.by_ref() {
+            let failed = item.is_err();
+            batch.push(item);
+            if failed || batch.len() >= batch_size {
+                break;
+            }
+        }
+        if batch.len() < batch_size || batch.last().is_some_and(Result::is_err) {
+            *chunk = None;
+        }
+    }
+
+    return batch;
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::*;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::PrefetchedChunk;
+
+    #[fixture]
+    fn thread_pool() -> Arc<rayon::ThreadPool> {
+        Arc::new(rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap())
+    }
+
+    #[rstest]
+    #[case(1, 0)]
+    #[case(3, 1)]
+    #[case(100, 4)]
+    fn test_prefetched_chunk(
+        thread_pool: Arc<rayon::ThreadPool>,
+        #[case] batch_size: usize,
+        #[case] queue_size: usize,
+    ) {
+        let saved: Vec<Result<i32, io::Error>> = Vec::from_iter((0..10).map(Ok));
+
+        let chunk = PrefetchedChunk::new(saved, thread_pool, batch_size, queue_size);
+        let restored: Result<Vec<i32>, _> = chunk.collect();
+
+        assert_eq!(restored.unwrap(), Vec::from_iter(0..10));
+    }
+
+    #[rstest]
+    fn test_prefetched_chunk_error(thread_pool: Arc<rayon::ThreadPool>) {
+        let saved = vec![Ok(1), Err(io::Error::other("test error")), Ok(2)];
+
+        let chunk = PrefetchedChunk::new(saved, thread_pool, 10, 1);
+        let restored: Vec<Result<i32, io::Error>> = chunk.collect();
+
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].as_ref().unwrap(), &1);
+        assert_eq!(restored[1].as_ref().unwrap_err().to_string(), "test error");
+    }
+
+    #[rstest]
+    fn test_prefetched_chunks_share_pool(thread_pool: Arc<rayon::ThreadPool>) {
+        // many more chunks than pool threads are read interleaved
+        let mut chunks = Vec::from_iter((0..50).map(|idx| {
+            let saved: Vec<Result<i32, io::Error>> = Vec::from_iter((0..10).map(|item| Ok(idx * 10 + item)));
+            PrefetchedChunk::new(saved, Arc::clone(&thread_pool), 3, 1)
+        }));
+
+        let mut restored = Vec::new();
+        for _ in 0..10 {
+            for chunk in chunks.iter_mut() {
+                restored.push(chunk.next().unwrap().unwrap());
+            }
+        }
+        assert!(chunks.iter_mut().all(|chunk| chunk.next().is_none()));
+
+        restored.sort();
+        assert_eq!(restored, Vec::from_iter(0..500));
+    }
+
+    #[rstest]
+    #[case(0)]
+    #[case(2)]
+    fn test_prefetched_chunk_queue_size(thread_pool: Arc<rayon::ThreadPool>, #[case] queue_size: usize) {
+        let read = Arc::new(AtomicUsize::new(0));
+        let read_items = Arc::clone(&read);
+        let saved = (0..100).map(move |item| {
+            read_items.fetch_add(1, Ordering::SeqCst);
+            return Ok::<_, io::Error>(item);
+        });
+
+        let mut chunk = PrefetchedChunk::new(saved, thread_pool, 5, queue_size);
+        assert_eq!(chunk.next().unwrap().unwrap(), 0);
+        thread::sleep(Duration::from_millis(50));
+
+        // the consumed batch and at most `queue_size` batches read in advance
+        assert!(read.load(Ordering::SeqCst) <= 5 * (queue_size + 1));
+        assert_eq!(chunk.map(Result::unwrap).collect::<Vec<_>>(), Vec::from_iter(1..100));
+    }
+
+    #[rstest]
+    #[should_panic(expected = "broken chunk")]
+    fn test_prefetched_chunk_panic(thread_pool: Arc<rayon::ThreadPool>) {
+        let saved = (0..10).map(|item| match item {
+            5 => panic!("broken chunk"),
+            item => Ok::<_, io::Error>(item),
+        });
+
+        let chunk = PrefetchedChunk::new(saved, thread_pool, 2, 1);
+        let _ = chunk.count();
+    }
+}
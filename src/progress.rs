@@ -0,0 +1,181 @@
+//! Sorting progress reporting.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Number of merged items the merge progress is reported after.
+pub const MERGE_PROGRESS_INTERVAL: u64 = 100_000;
+
+/// Sorting statistics.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SortStats {
+    /// Number of input items read.
+    pub items_read: u64,
+    /// Number of chunk files written, intermediate merge results included.
+    pub chunks_written: u64,
+    /// Number of bytes written to the chunk files.
+    pub bytes_spilled: u64,
+    /// Total time spent sorting chunk buffers.
+    pub sort_duration: Duration,
+    /// Total time spent writing chunk files. Intermediate merges are written while the merged chunks are read,
+    /// so their reading time is included as well.
+    pub spill_duration: Duration,
+    /// Number of intermediate merges reducing the number of chunks to the merge fan-in.
+    pub intermediate_merges: u64,
+    /// Number of items returned by the final merge.
+    pub items_merged: u64,
+    /// Time passed since the first merged item was requested till the final merge finished.
+    pub merge_duration: Duration,
+}
+
+/// Sorting progress observer. The observer methods are called by the sorting threads
+/// as the sorting advances, every method is passed the statistics collected so far.
+/// All the methods do nothing by default.
+pub trait ProgressObserver: Send + Sync {
+    /// Called after a chunk file is written.
+    fn on_chunk_written(&self, _stats: &SortStats) {}
+
+    /// Called after an intermediate merge is finished.
+    fn on_intermediate_merge(&self, _stats: &SortStats) {}
+
+    /// Called every [`MERGE_PROGRESS_INTERVAL`] items returned by the final merge.
+    fn on_merge_progress(&self, _stats: &SortStats) {}
+
+    /// Called once the final merge is finished.
+    fn on_merge_finished(&self, _stats: &SortStats) {}
+}
+
+/// Shared observers can be used to keep access to the observer passed to the sorter.
+impl<P: ProgressObserver + ?Sized> ProgressObserver for Arc<P> {
+    fn on_chunk_written(&self, stats: &SortStats) {
+        self.as_ref().on_chunk_written(stats)
+    }
+
+    fn on_intermediate_merge(&self, stats: &SortStats) {
+        self.as_ref().on_intermediate_merge(stats)
+    }
+
+    fn on_merge_progress(&self, stats: &SortStats) {
+        self.as_ref().on_merge_progress(stats)
+    }
+
+    fn on_merge_finished(&self, stats: &SortStats) {
+        self.as_ref().on_merge_finished(stats)
+    }
+}
+
+/// Progress of a single sorting shared by the sorting jobs and the final merger.
+#[derive(Default)]
+pub(crate) struct Progress {
+    stats: Mutex<SortStats>,
+    // the counters updated for every item are kept aside to avoid locking
+    items_read: AtomicU64,
+    items_merged: AtomicU64,
+    #[cfg(feature = "tracing")]
+    chunks_created: AtomicU64,
+    observer: Option<Arc<dyn ProgressObserver>>,
+}
+
+impl Progress {
+    pub(crate) fn new(observer: Option<Arc<dyn ProgressObserver>>) -> Self {
+        Progress {
+            observer,
+            ..Progress::default()
+        }
+    }
+
+    /// Returns the statistics collected so far.
+    pub(crate) fn stats(&self) -> SortStats {
+        let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        stats.items_read = self.items_read.load(Ordering::Relaxed);
+        stats.items_merged = self.items_merged.load(Ordering::Relaxed);
+
+        return stats;
+    }
+
+    /// Returns the index of a new chunk in the order the chunks creation starts.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn next_chunk_index(&self) -> u64 {
+        self.chunks_created.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn on_item_read(&self) {
+        self.items_read.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_buffer_sorted(&self, duration: Duration) {
+        self.update(|stats| stats.sort_duration += duration);
+    }
+
+    pub(crate) fn on_chunk_written(&self, bytes: u64, duration: Duration) {
+        self.update(|stats| {
+            stats.chunks_written += 1;
+            stats.bytes_spilled += bytes;
+            stats.spill_duration += duration;
+        });
+        self.notify(|observer, stats| observer.on_chunk_written(stats));
+    }
+
+    pub(crate) fn on_intermediate_merge(&self) {
+        self.update(|stats| stats.intermediate_merges += 1);
+        self.notify(|observer, stats| observer.on_intermediate_merge(stats));
+    }
+
+    pub(crate) fn on_item_merged(&self) {
+        let merged = self.items_merged.fetch_add(1, Ordering::Relaxed) + 1;
+        if merged % MERGE_PROGRESS_INTERVAL == 0 {
+            self.notify(|observer, stats| observer.on_merge_progress(stats));
+        }
+    }
+
+    pub(crate) fn on_merge_finished(&self, duration: Duration) {
+        self.update(|stats| stats.merge_duration = duration);
+        self.notify(|observer, stats| observer.on_merge_finished(stats));
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SortStats)) {
+        f(&mut self.stats.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    /// Calls the observer outside of the statistics lock.
+    fn notify(&self, f: impl FnOnce(&dyn ProgressObserver, &SortStats)) {
+        if let Some(observer) = &self.observer {
+            f(observer.as_ref(), &self.stats());
+        }
+    }
+}
+
+/// Final merge progress tracker.
+pub(crate) struct MergeTracker {
+    progress: Arc<Progress>,
+    started: Option<Instant>,
+    finished: bool,
+}
+
+impl MergeTracker {
+    pub(crate) fn new(progress: Arc<Progress>) -> Self {
+        MergeTracker {
+            progress,
+            started: None,
+            finished: false,
+        }
+    }
+
+    /// Returns the sorting statistics collected so far.
+    pub(crate) fn stats(&self) -> SortStats {
+        self.progress.stats()
+    }
+
+    /// Tracks a merger step, `merged` is `false` if the merger is exhausted.
+    pub(crate) fn on_next(&mut self, merged: bool) {
+        let started = *self.started.get_or_insert_with(Instant::now);
+
+        if merged {
+            self.progress.on_item_merged();
+        } else if !self.finished {
+            self.finished = true;
+            self.progress.on_merge_finished(started.elapsed());
+        }
+    }
+}
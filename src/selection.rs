@@ -0,0 +1,111 @@
+//! Replacement selection.
+
+use std::cmp::Ordering;
+
+use crate::heap::Heap;
+
+/// Selection heap entry.
+struct Entry<T> {
+    run: usize,
+    item: T,
+    seq: usize,
+}
+
+/// Replacement selection heap. Every time the smallest item of the current run is taken out
+/// it is replaced by a new input item. The new item joins the current run if it is not less than the taken one,
+/// otherwise it is postponed to the next run. On random input it produces runs twice as long as the heap
+/// on average, an already sorted input produces a single run.
+pub(crate) struct ReplacementSelection<T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    heap: Heap<Entry<T>>,
+    seq: usize,
+    compare: F,
+}
+
+impl<T, F> ReplacementSelection<T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    /// Creates a selection heap filled with the provided items all belonging to the first run.
+    pub(crate) fn new(items: impl IntoIterator<Item = T>, compare: F) -> Self {
+        let items = items.into_iter();
+        let mut selection = ReplacementSelection {
+            heap: Heap::with_capacity(items.size_hint().0),
+            seq: 0,
+            compare,
+        };
+        for item in items {
+            selection.push(0, item);
+        }
+
+        return selection;
+    }
+
+    /// Returns the run number the next taken item belongs to or [`None`] if the heap is empty.
+    pub(crate) fn run(&self) -> Option<usize> {
+        self.heap.peek().map(|entry| entry.run)
+    }
+
+    /// Takes the smallest item of the current run out of the heap replacing it with the provided one.
+    pub(crate) fn replace(&mut self, item: Option<T>) -> Option<T> {
+        let compare = &self.compare;
+        let entry = self.heap.pop(|a, b| Self::order(compare, a, b))?;
+
+        if let Some(item) = item {
+            let run = match (self.compare)(&item, &entry.item) {
+                Ordering::Less => entry.run + 1,
+                _ => entry.run,
+            };
+            self.push(run, item);
+        }
+
+        return Some(entry.item);
+    }
+
+    fn push(&mut self, run: usize, item: T) {
+        let entry = Entry {
+            run,
+            item,
+            seq: self.seq,
+        };
+        let compare = &self.compare;
+        self.heap.push(entry, |a, b| Self::order(compare, a, b));
+        self.seq += 1;
+    }
+
+    /// Orders entries by run number, then by item and then by arrival order which keeps the generated runs stable.
+    fn order(compare: &F, a: &Entry<T>, b: &Entry<T>) -> Ordering {
+        a.run
+            .cmp(&b.run)
+            .then_with(|| compare(&a.item, &b.item))
+            .then_with(|| a.seq.cmp(&b.seq))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::*;
+
+    use super::ReplacementSelection;
+
+    #[rstest]
+    #[case(vec![1, 2, 3, 4, 5, 6, 7, 8], vec![vec![1, 2, 3, 4, 5, 6, 7, 8]])]
+    #[case(vec![5, 3, 8, 1, 2, 9, 4, 7], vec![vec![3, 5, 8], vec![1, 2, 4, 7, 9]])]
+    #[case(vec![8, 7, 6, 5, 4, 3, 2, 1], vec![vec![7, 8], vec![5, 6], vec![3, 4], vec![1, 2]])]
+    fn test_replacement_selection(#[case] input: Vec<i32>, #[case] expected_runs: Vec<Vec<i32>>) {
+        let mut input = input.into_iter();
+        let mut selection = ReplacementSelection::new(input.by_ref().take(2), i32::cmp);
+
+        let mut actual_runs: Vec<Vec<i32>> = Vec::new();
+        while let Some(run) = selection.run() {
+            if actual_runs.len() <= run {
+                actual_runs.push(Vec::new());
+            }
+            actual_runs[run].push(selection.replace(input.next()).unwrap());
+        }
+
+        assert_eq!(actual_runs, expected_runs);
+    }
+}
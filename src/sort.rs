@@ -1,16 +1,37 @@
 //! External sorter.
 
 use log;
+use std::any::Any;
 use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Debug, Display};
 use std::io;
+use std::iter;
 use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use std::vec;
 
-use crate::chunk::{ExternalChunk, ExternalChunkError, RmpExternalChunk};
-use crate::merger::BinaryHeapMerger;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cancel::CancellationToken;
+use crate::chunk::{ExternalChunk, ExternalChunkError, ExternalChunkFile, RmpExternalChunk};
+use crate::group::GroupedBy;
+use crate::manifest::{Manifest, Persistence};
+use crate::merger::{Combined, Merger, MergerKind};
+use crate::prefetch::PrefetchedChunk;
+use crate::progress::{MergeTracker, Progress, ProgressObserver, SortStats};
+use crate::selection::ReplacementSelection;
 use crate::{ChunkBuffer, ChunkBufferBuilder, LimitedBufferBuilder};
 
 /// Sorting error.
@@ -28,6 +49,14 @@ pub enum SortError<S: Error, D: Error, I: Error> {
     DeserializationError(D),
     /// Input data stream error
     InputError(I),
+    /// Items comparison error.
+    CompareError(Box<dyn Error + Send + Sync>),
+    /// Sorting panicked, the panic message is kept.
+    Panicked(String),
+    /// Sorting cancelled by a cancellation token.
+    Cancelled,
+    /// Sorter settings conflicting with each other, the conflict is described.
+    InvalidConfig(String),
 }
 
 impl<S, D, I> Error for SortError<S, D, I>
@@ -37,14 +66,18 @@ where
     I: Error + 'static,
 {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        Some(match &self {
-            SortError::TempDir(err) => err,
-            SortError::ThreadPoolBuildError(err) => err,
-            SortError::IO(err) => err,
-            SortError::SerializationError(err) => err,
-            SortError::DeserializationError(err) => err,
-            SortError::InputError(err) => err,
-        })
+        match &self {
+            SortError::TempDir(err) => Some(err),
+            SortError::ThreadPoolBuildError(err) => Some(err),
+            SortError::IO(err) => Some(err),
+            SortError::SerializationError(err) => Some(err),
+            SortError::DeserializationError(err) => Some(err),
+            SortError::InputError(err) => Some(err),
+            SortError::CompareError(err) => Some(err.as_ref()),
+            SortError::Panicked(_) => None,
+            SortError::Cancelled => None,
+            SortError::InvalidConfig(_) => None,
+        }
     }
 }
 
@@ -57,10 +90,420 @@ impl<S: Error, D: Error, I: Error> Display for SortError<S, D, I> {
             SortError::SerializationError(err) => write!(f, "data serialization error: {}", err),
             SortError::DeserializationError(err) => write!(f, "data deserialization error: {}", err),
             SortError::InputError(err) => write!(f, "input data stream error: {}", err),
+            SortError::CompareError(err) => write!(f, "items comparison failed: {}", err),
+            SortError::Panicked(msg) => write!(f, "sorting panicked: {}", msg),
+            SortError::Cancelled => write!(f, "sorting cancelled"),
+            SortError::InvalidConfig(msg) => write!(f, "invalid sorter configuration: {}", msg),
+        }
+    }
+}
+
+/// Function combining the accumulated item with the next equal one.
+type CombineFn<T> = dyn Fn(T, T) -> T + Send + Sync;
+
+/// Equal items combining settings.
+struct Combiner<T> {
+    /// Function combining equal items.
+    combine: Arc<CombineFn<T>>,
+    /// Wraps the function to be owned by the merged items iterator. It is instantiated by the builder
+    /// where the items are known to be `'static`, so the sorting methods don't require it.
+    boxed: fn(&Arc<CombineFn<T>>) -> Box<CombineFn<T>>,
+}
+
+impl<T> Clone for Combiner<T> {
+    fn clone(&self) -> Self {
+        Combiner {
+            combine: Arc::clone(&self.combine),
+            boxed: self.boxed,
+        }
+    }
+}
+
+/// Job sorting and dumping a chunk buffer on the thread pool.
+type ChunkJob<'scope, S> = Box<dyn FnOnce() -> Result<ExternalChunkFile, ExternalChunkError<S>> + Send + 'scope>;
+
+/// Receiver of the chunk created by a [`ChunkJob`].
+type ChunkReceiver<S> = mpsc::Receiver<Result<ExternalChunkFile, ExternalChunkError<S>>>;
+
+/// Pipelined chunk creation settings.
+struct Pipeline<S: Error> {
+    /// Maximum number of chunk buffers sorted and dumped concurrently with input reading.
+    depth: usize,
+    /// Spawns a chunk job. It is instantiated by the builder where the job result is known to be sendable,
+    /// so the sorting methods don't require it.
+    spawn: for<'scope> fn(&rayon::Scope<'scope>, ChunkJob<'scope, S>) -> ChunkReceiver<S>,
+}
+
+impl<S: Error> Clone for Pipeline<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: Error> Copy for Pipeline<S> {}
+
+/// Merge phase prefetching settings.
+#[allow(clippy::type_complexity)]
+struct Prefetch<T, C: ExternalChunk<T>> {
+    /// Number of items read at once.
+    batch_size: usize,
+    /// Maximum number of batches read in advance.
+    queue_size: usize,
+    /// Creates a prefetched chunk. It is instantiated by the builder where the chunk is known
+    /// to be sendable to the thread pool, so the sorting methods don't require it.
+    open: fn(C, Arc<rayon::ThreadPool>, usize, usize) -> PrefetchedChunk<T, C::DeserializationError>,
+}
+
+impl<T, C: ExternalChunk<T>> Clone for Prefetch<T, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, C: ExternalChunk<T>> Copy for Prefetch<T, C> {}
+
+/// State of a single sorting passed down to its phases.
+pub(crate) struct SortContext<'a, T> {
+    /// Progress of the sorting.
+    pub(crate) progress: Arc<Progress>,
+    /// Persistent directory the chunks are named in, [`None`] if the chunks are temporary.
+    persistence: Option<&'a Persistence>,
+    /// Samples of the created chunks, [`None`] if the sorting doesn't pick key range splitters.
+    samples: Option<&'a ChunkSamples<T>>,
+    /// Failure of the fallible compare function, [`None`] if the compare function can't fail.
+    compare_failure: Option<Arc<CompareFailure>>,
+    /// Token cancelling the sorting, [`None`] if the sorting can't be cancelled.
+    cancellation: Option<CancellationToken>,
+}
+
+impl<'a, T> SortContext<'a, T> {
+    /// Returns the context of the same sorting creating its chunks in the persistent directory.
+    fn persistent(&self, persistence: &'a Persistence) -> Self {
+        SortContext {
+            persistence: Some(persistence),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the context of the same sorting sampling the created chunks.
+    fn sampled(&self, samples: &'a ChunkSamples<T>) -> Self {
+        SortContext {
+            samples: Some(samples),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the context of the same sorting using a fallible compare function.
+    fn failable(&self, compare_failure: &Arc<CompareFailure>) -> Self {
+        SortContext {
+            compare_failure: Some(Arc::clone(compare_failure)),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the context of the same sorting cancelled by the token instead of the sorter one.
+    fn cancellable(&self, token: &CancellationToken) -> Self {
+        SortContext {
+            cancellation: Some(token.clone()),
+            ..self.clone()
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+}
+
+impl<T> Clone for SortContext<'_, T> {
+    fn clone(&self) -> Self {
+        SortContext {
+            progress: Arc::clone(&self.progress),
+            persistence: self.persistence,
+            samples: self.samples,
+            compare_failure: self.compare_failure.clone(),
+            cancellation: self.cancellation.clone(),
+        }
+    }
+}
+
+/// The first error returned by a fallible compare function.
+pub(crate) struct CompareFailure {
+    failed: AtomicBool,
+    error: Mutex<Option<Box<dyn Error + Send + Sync>>>,
+}
+
+impl CompareFailure {
+    fn new() -> Self {
+        CompareFailure {
+            failed: AtomicBool::new(false),
+            error: Mutex::new(None),
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.failed.load(atomic::Ordering::Acquire)
+    }
+
+    /// Stores the error unless an error has already been stored.
+    fn set(&self, err: Box<dyn Error + Send + Sync>) {
+        let mut error = self.error.lock().unwrap_or_else(PoisonError::into_inner);
+        if !self.is_set() {
+            *error = Some(err);
+            self.failed.store(true, atomic::Ordering::Release);
+        }
+    }
+
+    fn take(&self) -> Option<Box<dyn Error + Send + Sync>> {
+        if !self.is_set() {
+            return None;
+        }
+        return self.error.lock().unwrap_or_else(PoisonError::into_inner).take();
+    }
+}
+
+/// Default chunk file read/write buffer size.
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+/// Minimal chunk file read buffer size the merge phase memory is split into.
+const MIN_READ_BUF_SIZE: usize = 4 * 1024;
+
+/// Number of chunk items sampled per key range to pick the range splitters in partitioned sorting.
+const SAMPLES_PER_PARTITION: usize = 64;
+
+/// Sorted data source the merger reads items from.
+pub enum MergeSource<T, C: ExternalChunk<T>> {
+    /// Chunk read by the merger itself.
+    Chunk(C),
+    /// Chunk read in advance on the sorter thread pool.
+    Prefetched(PrefetchedChunk<T, C::DeserializationError>),
+    /// Sorted items kept in memory.
+    Memory(vec::IntoIter<T>),
+}
+
+impl<T, C: ExternalChunk<T>> Iterator for MergeSource<T, C> {
+    type Item = Result<T, C::DeserializationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            MergeSource::Chunk(chunk) => chunk.next(),
+            MergeSource::Prefetched(chunk) => chunk.next(),
+            MergeSource::Memory(items) => items.next().map(Ok),
+        }
+    }
+}
+
+/// Sorted items source of a [`SortedIterator`].
+#[allow(clippy::type_complexity)]
+enum SortedItems<T, F, C>
+where
+    F: Fn(&T, &T) -> Ordering,
+    C: ExternalChunk<T>,
+{
+    /// The input fitted in a single buffer and was sorted in memory.
+    Memory(vec::IntoIter<T>),
+    /// The input was dumped to sorted chunks which are merged.
+    Merged(Merger<T, C::DeserializationError, F, MergeSource<T, C>>),
+    /// The input was dumped to sorted chunks which are merged combining equal items.
+    Combined(Combined<T, C::DeserializationError, F, MergeSource<T, C>, Box<CombineFn<T>>>),
+}
+
+impl<T, F, C> Iterator for SortedItems<T, F, C>
+where
+    F: Fn(&T, &T) -> Ordering,
+    C: ExternalChunk<T>,
+{
+    type Item = Result<T, C::DeserializationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedItems::Memory(items) => items.next().map(Ok),
+            SortedItems::Merged(merger) => merger.next(),
+            SortedItems::Combined(merger) => merger.next(),
+        }
+    }
+}
+
+/// Sorted data iterator.
+/// A panic raised while the items are merged is returned as [`SortError::Panicked`] and a cancellation
+/// of the sorting as [`SortError::Cancelled`], either of them ends the iteration.
+pub struct SortedIterator<T, E, F, C>
+where
+    F: Fn(&T, &T) -> Ordering,
+    C: ExternalChunk<T>,
+{
+    items: SortedItems<T, F, C>,
+    cancellation: Option<CancellationToken>,
+    compare_failure: Option<Arc<CompareFailure>>,
+    tracker: MergeTracker,
+    finished: bool,
+    input_error: PhantomData<E>,
+}
+
+impl<T, E, F, C> SortedIterator<T, E, F, C>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: ExternalChunk<T>,
+{
+    fn new(
+        items: SortedItems<T, F, C>,
+        cancellation: Option<CancellationToken>,
+        compare_failure: Option<Arc<CompareFailure>>,
+        progress: Arc<Progress>,
+    ) -> Self {
+        return SortedIterator {
+            items,
+            cancellation,
+            compare_failure,
+            tracker: MergeTracker::new(progress),
+            finished: false,
+            input_error: PhantomData,
+        };
+    }
+
+    /// Checks if the input fitted in a single buffer and was sorted in memory.
+    pub fn is_in_memory(&self) -> bool {
+        return matches!(self.items, SortedItems::Memory(_));
+    }
+
+    /// Groups consecutive sorted items with equal keys. The data should be sorted by the same key.
+    ///
+    /// # Arguments
+    /// * `key_fn` - Function to be used to extract item key
+    #[allow(clippy::type_complexity)]
+    pub fn group_by_key<K, KF>(
+        self,
+        key_fn: KF,
+    ) -> GroupedBy<T, SortError<C::SerializationError, C::DeserializationError, E>, Self, K, KF>
+    where
+        K: Clone + PartialEq,
+        KF: Fn(&T) -> K,
+    {
+        return GroupedBy::new(self, key_fn);
+    }
+
+    /// Returns the statistics of the sorting the iterator belongs to.
+    /// The statistics are complete once the iterator is exhausted.
+    pub fn stats(&self) -> SortStats {
+        return self.tracker.stats();
+    }
+}
+
+impl<T, E, F, C> Iterator for SortedIterator<T, E, F, C>
+where
+    E: Error,
+    F: Fn(&T, &T) -> Ordering,
+    C: ExternalChunk<T>,
+{
+    type Item = Result<T, SortError<C::SerializationError, C::DeserializationError, E>>;
+
+    /// Returns the next item in the order defined by the compare function.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        // nothing is merged after a panic so the merger is safe to be unwound
+        let item = match panic::catch_unwind(AssertUnwindSafe(|| self.items.next())) {
+            Ok(item) => item,
+            Err(payload) => {
+                self.finished = true;
+                return Some(Err(SortError::Panicked(panic_message(payload))));
+            }
+        };
+        // the merger stops silently once the sorting is cancelled
+        if self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+            self.finished = true;
+            return Some(Err(SortError::Cancelled));
+        }
+        // the items merged after a compare function failure are not ordered
+        if let Some(err) = self.compare_failure.as_ref().and_then(|failure| failure.take()) {
+            self.finished = true;
+            return Some(Err(SortError::CompareError(err)));
         }
+        self.tracker.on_next(item.is_some());
+
+        return item.map(|item| item.map_err(SortError::DeserializationError));
+    }
+}
+
+/// Sorted data iterator of [`ExternalSorter::sort_by_cached_key`]. The cached keys are stripped from the items.
+pub struct SortedByCachedKey<K, T, E, F, C>
+where
+    F: Fn(&(K, T), &(K, T)) -> Ordering,
+    C: ExternalChunk<(K, T)>,
+{
+    items: SortedIterator<(K, T), E, F, C>,
+}
+
+impl<K, T, E, F, C> SortedByCachedKey<K, T, E, F, C>
+where
+    E: Error,
+    F: Fn(&(K, T), &(K, T)) -> Ordering,
+    C: ExternalChunk<(K, T)>,
+{
+    /// Checks if the input fitted in a single buffer and was sorted in memory.
+    pub fn is_in_memory(&self) -> bool {
+        return self.items.is_in_memory();
+    }
+
+    /// Groups consecutive sorted items with equal keys. The data should be sorted by the same key.
+    ///
+    /// # Arguments
+    /// * `key_fn` - Function to be used to extract item key
+    #[allow(clippy::type_complexity)]
+    pub fn group_by_key<GK, KF>(
+        self,
+        key_fn: KF,
+    ) -> GroupedBy<T, SortError<C::SerializationError, C::DeserializationError, E>, Self, GK, KF>
+    where
+        GK: Clone + PartialEq,
+        KF: Fn(&T) -> GK,
+    {
+        return GroupedBy::new(self, key_fn);
+    }
+
+    /// Returns the statistics of the sorting the iterator belongs to.
+    /// The statistics are complete once the iterator is exhausted.
+    pub fn stats(&self) -> SortStats {
+        return self.items.stats();
+    }
+}
+
+impl<K, T, E, F, C> Iterator for SortedByCachedKey<K, T, E, F, C>
+where
+    E: Error,
+    F: Fn(&(K, T), &(K, T)) -> Ordering,
+    C: ExternalChunk<(K, T)>,
+{
+    type Item = Result<T, SortError<C::SerializationError, C::DeserializationError, E>>;
+
+    /// Returns the next item in the order of the cached keys.
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.items.next().map(|item| item.map(|(_, item)| item));
     }
 }
 
+/// Sorted chunks (runs) generation strategy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunGeneration {
+    /// The chunk buffer is filled with the input items, sorted and dumped.
+    /// Every chunk holds exactly one buffer of items.
+    #[default]
+    SortBuffer,
+    /// Replacement selection is used. The input items pass through a selection heap holding as many items
+    /// as the first filled chunk buffer does and are dumped in sorted order as they leave the heap.
+    /// Chunks are twice as long as the buffer on average, an already sorted input produces a single chunk.
+    /// Chunks are created sequentially, pipelining is not applied.
+    ReplacementSelection,
+    /// The chunk buffer is filled and sorted as in [`RunGeneration::SortBuffer`] strategy but ascending runs
+    /// of the input items are tracked as they are buffered. If the run a filled buffer ends with takes
+    /// at least half of the buffer the run is not sorted and the following input items are streamed
+    /// to the same chunk as long as the order holds, the buffer items preceding the run are sorted and dumped
+    /// as a separate chunk. An ordered input fitting in the buffer is neither sorted nor dumped.
+    /// Chunks are created sequentially, pipelining is not applied.
+    NaturalRuns,
+}
+
 /// External sorter builder. Provides methods for [`ExternalSorter`] initialization.
 #[derive(Clone)]
 pub struct ExternalSorterBuilder<T, E, B = LimitedBufferBuilder, C = RmpExternalChunk<T>>
@@ -78,6 +521,28 @@ where
     rw_buf_size: Option<usize>,
     /// Chunk buffer builder.
     buffer_builder: B,
+    /// Maximum number of chunks merged at once.
+    max_merge_fanin: Option<usize>,
+    /// Pipelined chunk creation settings.
+    pipeline: Option<Pipeline<C::SerializationError>>,
+    /// Merge phase prefetching settings.
+    prefetch: Option<Prefetch<T, C>>,
+    /// Merger implementation.
+    merger_kind: MergerKind,
+    /// Sorted chunks generation strategy.
+    run_generation: RunGeneration,
+    /// Total memory budget in bytes.
+    memory_budget: Option<u64>,
+    /// Function combining equal items.
+    combiner: Option<Combiner<T>>,
+    /// If unstable sorting is used.
+    unstable: bool,
+    /// Token cancelling the sorting.
+    cancellation: Option<CancellationToken>,
+    /// Sorting progress observer.
+    progress_observer: Option<Arc<dyn ProgressObserver>>,
+    /// Persistent directory and comparator identifier.
+    persistence: Option<(Box<Path>, String)>,
 
     /// External chunk type.
     external_chunk_type: PhantomData<C>,
@@ -100,15 +565,55 @@ where
     }
 
     /// Builds an [`ExternalSorter`] instance using provided configuration.
+    #[allow(clippy::type_complexity)]
     pub fn build(
         self,
     ) -> Result<ExternalSorter<T, E, B, C>, SortError<C::SerializationError, C::DeserializationError, E>> {
-        ExternalSorter::new(
+        let mut sorter = ExternalSorter::new(
             self.threads_number,
             self.tmp_dir.as_deref(),
             self.buffer_builder,
             self.rw_buf_size,
-        )
+        )?;
+        sorter.max_merge_fanin = self.max_merge_fanin;
+        sorter.pipeline = self.pipeline;
+        sorter.prefetch = self.prefetch;
+        sorter.merger_kind = self.merger_kind;
+        sorter.run_generation = self.run_generation;
+        sorter.memory_budget = self.memory_budget;
+        sorter.combiner = self.combiner;
+        sorter.unstable = self.unstable;
+        sorter.cancellation = self.cancellation;
+        sorter.progress_observer = self.progress_observer;
+        sorter.persistence = self
+            .persistence
+            .map(|(dir, comparator_id)| Persistence::new(&dir, &comparator_id));
+
+        if let Some(budget) = sorter.memory_budget {
+            let buffers_memory = sorter.buffers_memory(mem::size_of::<T>() as u64);
+            if buffers_memory > budget {
+                return Err(SortError::InvalidConfig(format!(
+                    "memory budget of {} bytes does not cover chunk buffers taking {} bytes",
+                    budget, buffers_memory
+                )));
+            }
+        }
+
+        if sorter.persistence.is_some() {
+            if sorter.pipeline.is_some() {
+                return Err(SortError::InvalidConfig(
+                    "pipelined chunk creation is not supported in the persistent mode".to_string(),
+                ));
+            }
+            if sorter.run_generation != RunGeneration::SortBuffer {
+                return Err(SortError::InvalidConfig(format!(
+                    "{:?} run generation is not supported in the persistent mode",
+                    sorter.run_generation
+                )));
+            }
+        }
+
+        return Ok(sorter);
     }
 
     /// Sets number of threads to be used to sort data in parallel.
@@ -134,6 +639,181 @@ where
         self.rw_buf_size = Some(buf_size);
         return self;
     }
+
+    /// Sets maximum number of chunks merged at once. If more chunks are created intermediate merge passes
+    /// are performed until at most `fanin` chunks remain for the final merge.
+    /// The last chunk kept in memory is not counted.
+    ///
+    /// # Panics
+    /// Panics if `fanin` is less than 2.
+    pub fn with_max_merge_fanin(mut self, fanin: usize) -> ExternalSorterBuilder<T, E, B, C> {
+        assert!(fanin >= 2, "merge fan-in must be at least 2");
+        self.max_merge_fanin = Some(fanin);
+        return self;
+    }
+
+    /// Sets merger implementation to be used to merge sorted chunks.
+    pub fn with_merger(mut self, merger_kind: MergerKind) -> ExternalSorterBuilder<T, E, B, C> {
+        self.merger_kind = merger_kind;
+        return self;
+    }
+
+    /// Sets sorted chunks generation strategy.
+    pub fn with_run_generation(mut self, run_generation: RunGeneration) -> ExternalSorterBuilder<T, E, B, C> {
+        self.run_generation = run_generation;
+        return self;
+    }
+
+    /// Sets total memory budget in bytes covering both sorting phases. Chunk buffers, chunk file read/write buffers
+    /// and the items held by the merger are taken into account. The memory taken by an item of a buffer limited
+    /// by items count or held by the merger is estimated as the item size plus the average size of the items
+    /// dumped to the chunks which approximates the heap memory an item owns.
+    /// The stable sorting scratch space of about a buffer size is counted as well unless unstable sorting is used.
+    /// The memory left after the chunk buffers is split across the merged chunks read buffers.
+    /// If it is not enough to give every chunk a minimal read buffer the merge fan-in is limited accordingly.
+    ///
+    /// The chunk buffers must be limited, [`ExternalSorterBuilder::build`] returns [`SortError::InvalidConfig`]
+    /// if the budget does not cover them.
+    pub fn with_memory_budget(mut self, budget: u64) -> ExternalSorterBuilder<T, E, B, C> {
+        self.memory_budget = Some(budget);
+        return self;
+    }
+
+    /// Enables unstable sorting. Buffers are sorted using unstable parallel sorting which does not allocate
+    /// a scratch buffer of the buffer size and the mergers do not order equal items by the chunk they are taken from.
+    /// Equal items are returned in arbitrary order then.
+    pub fn with_unstable_sort(mut self, unstable: bool) -> ExternalSorterBuilder<T, E, B, C> {
+        self.unstable = unstable;
+        return self;
+    }
+
+    /// Sets a token the sorting can be cancelled with. The token is checked between input items,
+    /// before every chunk is created and on every merged item. Once it is cancelled the sorting
+    /// returns [`SortError::Cancelled`] removing the temporary chunk files, an iterator returned before
+    /// the cancellation returns it as its last item. Every sorting started after the cancellation is cancelled
+    /// as well unless it is given its own token by [`ExternalSorter::sort_by_cancellable`].
+    pub fn with_cancellation(mut self, token: CancellationToken) -> ExternalSorterBuilder<T, E, B, C> {
+        self.cancellation = Some(token);
+        return self;
+    }
+
+    /// Sets an observer the sorting progress is reported to. The statistics passed to the observer
+    /// are collected per sorting, statistics of sortings run concurrently by the same sorter get mixed up.
+    pub fn with_progress_observer(
+        mut self,
+        observer: impl ProgressObserver + 'static,
+    ) -> ExternalSorterBuilder<T, E, B, C> {
+        self.progress_observer = Some(Arc::new(observer));
+        return self;
+    }
+
+    /// Enables persistent mode in which [`ExternalSorter::sort_by`] (and the methods based on it) keeps
+    /// the chunk files in `dir` under stable names along with a run manifest recording the input offset,
+    /// the chunks and `comparator_id`. A sorting restarted after a crash or a cancellation reopens the recorded
+    /// chunks, skips the input items already dumped to them and continues from the last chunk,
+    /// or goes straight to the merge if the whole input was dumped. The same input has to be passed again.
+    ///
+    /// The last chunk is dumped as well instead of being kept in memory. Other run generation strategies and
+    /// pipelining are not supported in this mode, [`ExternalSorterBuilder::build`] returns
+    /// [`SortError::InvalidConfig`] if they are set. So do the sorting methods not based on
+    /// [`ExternalSorter::sort_by`], the sinks and the async sorter. The persisted data is kept
+    /// until [`ExternalSorter::clear_persisted`] is called.
+    ///
+    /// # Arguments
+    /// * `dir` - Directory the chunk files and the manifest are kept in
+    /// * `comparator_id` - Identifier of the compare function, sorting is not resumed if it is changed
+    pub fn with_persistence(mut self, dir: &Path, comparator_id: &str) -> ExternalSorterBuilder<T, E, B, C> {
+        self.persistence = Some((dir.into(), comparator_id.to_string()));
+        return self;
+    }
+}
+
+impl<T, E, B, C> ExternalSorterBuilder<T, E, B, C>
+where
+    T: Send,
+    E: Error,
+    B: ChunkBufferBuilder<T>,
+    C: ExternalChunk<T>,
+    C::SerializationError: Send + 'static,
+{
+    /// Enables pipelined chunk creation. Filled chunk buffers are sorted and dumped on the sorter thread pool
+    /// while the next buffer is being filled. At most `depth` buffers are processed at the same time,
+    /// so up to `depth + 1` chunk buffers may reside in memory.
+    ///
+    /// # Panics
+    /// Panics if `depth` is 0.
+    pub fn with_pipeline_depth(mut self, depth: usize) -> ExternalSorterBuilder<T, E, B, C> {
+        assert!(depth >= 1, "pipeline depth must be at least 1");
+        self.pipeline = Some(Pipeline {
+            depth,
+            spawn: spawn_chunk_job,
+        });
+        return self;
+    }
+}
+
+impl<T, E, B, C> ExternalSorterBuilder<T, E, B, C>
+where
+    T: Send + 'static,
+    E: Error,
+    B: ChunkBufferBuilder<T>,
+    C: ExternalChunk<T>,
+{
+    /// Sets function combining equal items, equality is determined by the compare function.
+    /// Equal items are combined in every chunk before it is dumped and again across chunks during the merge,
+    /// the function is passed the accumulated item and the next equal one in the order of the sorted stream.
+    /// Partitioned sorting puts equal items into the same key range, so they are combined the same way.
+    pub fn with_combiner(
+        mut self,
+        combine: impl Fn(T, T) -> T + Send + Sync + 'static,
+    ) -> ExternalSorterBuilder<T, E, B, C> {
+        self.combiner = Some(Combiner {
+            combine: Arc::new(combine),
+            boxed: |combine| {
+                let combine = Arc::clone(combine);
+                return Box::new(move |acc, item| combine(acc, item));
+            },
+        });
+        return self;
+    }
+
+    /// Enables unique mode in which only the first of equal items in the order of the sorted stream is kept,
+    /// equality is determined by the compare function. With unstable sorting it may be any of them.
+    /// It is a shortcut for a combiner dropping every item but the first one, so it replaces the combiner
+    /// set before. Disabling it removes any combiner.
+    pub fn with_unique(self, unique: bool) -> ExternalSorterBuilder<T, E, B, C> {
+        return match unique {
+            true => self.with_combiner(|first, _| first),
+            false => ExternalSorterBuilder { combiner: None, ..self },
+        };
+    }
+}
+
+impl<T, E, B, C> ExternalSorterBuilder<T, E, B, C>
+where
+    T: Send + 'static,
+    E: Error,
+    B: ChunkBufferBuilder<T>,
+    C: ExternalChunk<T> + Send + 'static,
+    C::DeserializationError: Send + 'static,
+{
+    /// Enables chunk prefetching during the merge phase. Every chunk is read by the sorter thread pool
+    /// decoding `batch_size` items at once and keeping up to `queue_size` batches ready for the merger,
+    /// so the merging thread does not block on I/O and deserialization. A chunk is read by a single pool job
+    /// at a time which takes a pool thread only while a batch is being read, so no thread is dedicated to
+    /// a chunk and no thread waits for another one reading the same chunk.
+    ///
+    /// # Panics
+    /// Panics if `batch_size` is 0.
+    pub fn with_prefetch(mut self, batch_size: usize, queue_size: usize) -> ExternalSorterBuilder<T, E, B, C> {
+        assert!(batch_size >= 1, "prefetch batch size must be at least 1");
+        self.prefetch = Some(Prefetch {
+            batch_size,
+            queue_size,
+            open: PrefetchedChunk::new::<C>,
+        });
+        return self;
+    }
 }
 
 impl<T, E, B, C> Default for ExternalSorterBuilder<T, E, B, C>
@@ -149,6 +829,17 @@ where
             tmp_dir: None,
             rw_buf_size: None,
             buffer_builder: B::default(),
+            max_merge_fanin: None,
+            pipeline: None,
+            prefetch: None,
+            merger_kind: MergerKind::default(),
+            run_generation: RunGeneration::default(),
+            memory_budget: None,
+            combiner: None,
+            unstable: false,
+            cancellation: None,
+            progress_observer: None,
+            persistence: None,
             external_chunk_type: PhantomData,
             item_type: PhantomData,
             input_error_type: PhantomData,
@@ -165,13 +856,35 @@ where
     C: ExternalChunk<T>,
 {
     /// Sorting thread pool.
-    thread_pool: rayon::ThreadPool,
+    thread_pool: Arc<rayon::ThreadPool>,
     /// Directory to be used to store temporary data.
-    tmp_dir: tempfile::TempDir,
+    tmp_dir: Arc<tempfile::TempDir>,
     /// Chunk buffer builder.
-    buffer_builder: B,
+    pub(crate) buffer_builder: B,
     /// Chunk file read/write buffer size.
     rw_buf_size: Option<usize>,
+    /// Maximum number of chunks merged at once.
+    max_merge_fanin: Option<usize>,
+    /// Pipelined chunk creation settings.
+    pipeline: Option<Pipeline<C::SerializationError>>,
+    /// Merge phase prefetching settings.
+    prefetch: Option<Prefetch<T, C>>,
+    /// Merger implementation.
+    merger_kind: MergerKind,
+    /// Sorted chunks generation strategy.
+    run_generation: RunGeneration,
+    /// Total memory budget in bytes.
+    memory_budget: Option<u64>,
+    /// Function combining equal items.
+    combiner: Option<Combiner<T>>,
+    /// If unstable sorting is used.
+    unstable: bool,
+    /// Token cancelling the sorting.
+    cancellation: Option<CancellationToken>,
+    /// Sorting progress observer.
+    progress_observer: Option<Arc<dyn ProgressObserver>>,
+    /// Persistent directory the chunks are kept in.
+    persistence: Option<Persistence>,
 
     /// External chunk type.
     external_chunk_type: PhantomData<C>,
@@ -206,8 +919,19 @@ where
         return Ok(ExternalSorter {
             rw_buf_size,
             buffer_builder,
-            thread_pool: Self::init_thread_pool(threads_number)?,
-            tmp_dir: Self::init_tmp_directory(tmp_path)?,
+            max_merge_fanin: None,
+            pipeline: None,
+            prefetch: None,
+            merger_kind: MergerKind::default(),
+            run_generation: RunGeneration::default(),
+            memory_budget: None,
+            combiner: None,
+            unstable: false,
+            cancellation: None,
+            progress_observer: None,
+            persistence: None,
+            thread_pool: Arc::new(Self::init_thread_pool(threads_number)?),
+            tmp_dir: Arc::new(Self::init_tmp_directory(tmp_path)?),
             external_chunk_type: PhantomData,
             item_type: PhantomData,
             input_error_type: PhantomData,
@@ -252,11 +976,12 @@ where
     ///
     /// # Arguments
     /// * `input` - Input stream data to be fetched from
+    #[allow(clippy::type_complexity)]
     pub fn sort<I>(
         &self,
         input: I,
     ) -> Result<
-        BinaryHeapMerger<T, C::DeserializationError, impl Fn(&T, &T) -> Ordering + Copy, C>,
+        SortedIterator<T, E, impl Fn(&T, &T) -> Ordering, C>,
         SortError<C::SerializationError, C::DeserializationError, E>,
     >
     where
@@ -266,115 +991,3334 @@ where
         self.sort_by(input, T::cmp)
     }
 
-    /// Sorts data from the input using a custom compare function.
+    /// Sorts data from the input using a key extraction function.
     /// Returns an iterator that can be used to get sorted data stream.
+    /// The key is extracted on every comparison, see [`ExternalSorter::sort_by_cached_key`] for expensive keys.
     ///
     /// # Arguments
     /// * `input` - Input stream data to be fetched from
-    /// * `compare` - Function be be used to compare items
-    pub fn sort_by<I, F>(
+    /// * `key_fn` - Function to be used to extract item key
+    #[allow(clippy::type_complexity)]
+    pub fn sort_by_key<I, K, KF>(
         &self,
         input: I,
-        compare: F,
+        key_fn: KF,
     ) -> Result<
-        BinaryHeapMerger<T, C::DeserializationError, F, C>,
+        SortedIterator<T, E, impl Fn(&T, &T) -> Ordering, C>,
         SortError<C::SerializationError, C::DeserializationError, E>,
     >
     where
         I: IntoIterator<Item = Result<T, E>>,
-        F: Fn(&T, &T) -> Ordering + Sync + Send + Copy,
+        K: Ord,
+        KF: Fn(&T) -> K + Sync + Send,
     {
-        let mut chunk_buf = self.buffer_builder.build();
-        let mut external_chunks = Vec::new();
-
-        for item in input.into_iter() {
-            match item {
-                Ok(item) => chunk_buf.push(item),
-                Err(err) => return Err(SortError::InputError(err)),
-            }
-
-            if chunk_buf.is_full() {
-                external_chunks.push(self.create_chunk(chunk_buf, compare)?);
-                chunk_buf = self.buffer_builder.build();
-            }
-        }
+        self.sort_by(input, move |a, b| key_fn(a).cmp(&key_fn(b)))
+    }
 
-        if chunk_buf.len() > 0 {
-            external_chunks.push(self.create_chunk(chunk_buf, compare)?);
-        }
+    /// Creates a sink items can be pushed to one by one instead of being fetched from an input stream.
+    #[allow(clippy::type_complexity)]
+    pub fn sink(&self) -> SortSink<'_, T, E, B, C, fn(&T, &T) -> Ordering>
+    where
+        T: Ord,
+    {
+        self.sink_by(T::cmp)
+    }
 
-        log::debug!("external sort preparation done");
+    /// Creates a sink items can be pushed to one by one instead of being fetched from an input stream.
+    /// The items are sorted using a custom compare function.
+    ///
+    /// # Arguments
+    /// * `compare` - Function be be used to compare items
+    pub fn sink_by<F>(&self, compare: F) -> SortSink<'_, T, E, B, C, F>
+    where
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let mut sink = self.new_sink(self.new_context(), compare);
+        sink.error = self.check_not_persistent("sink").err();
 
-        return Ok(BinaryHeapMerger::new(external_chunks, compare));
+        return sink;
     }
 
-    fn create_chunk<F>(
+    /// Creates a sink adding the items to the provided sorting.
+    fn new_sink<'a, F>(&'a self, ctx: SortContext<'a, T>, compare: F) -> SortSink<'a, T, E, B, C, F>
+    where
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        SortSink {
+            sorter: self,
+            buffer: self.buffer_builder.build(),
+            chunks: Vec::new(),
+            compare,
+            ctx,
+            error: None,
+        }
+    }
+
+    /// Sorts data from the input using a custom compare function.
+    /// Returns an iterator that can be used to get sorted data stream.
+    /// The last chunk is not dumped but kept in memory and merged with the dumped ones.
+    ///
+    /// A panic raised by the compare function or the chunk implementation while the input is sorted, dumped
+    /// or merged is returned as [`SortError::Panicked`] either by this method or by the returned iterator.
+    ///
+    /// # Arguments
+    /// * `input` - Input stream data to be fetched from
+    /// * `compare` - Function be be used to compare items
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "sort", skip_all, fields(threads = self.thread_pool.current_num_threads()))
+    )]
+    #[allow(clippy::type_complexity)]
+    pub fn sort_by<I, F>(
         &self,
-        mut buffer: impl ChunkBuffer<T>,
+        input: I,
         compare: F,
-    ) -> Result<C, SortError<C::SerializationError, C::DeserializationError, E>>
+    ) -> Result<SortedIterator<T, E, F, C>, SortError<C::SerializationError, C::DeserializationError, E>>
     where
+        I: IntoIterator<Item = Result<T, E>>,
         F: Fn(&T, &T) -> Ordering + Sync + Send,
     {
-        log::debug!("sorting chunk data ...");
-        self.thread_pool.install(|| {
-            buffer.par_sort_by(compare);
+        catch_panic(|| self.sort_in_context(&self.new_context(), input, compare))
+    }
+
+    /// Sorts data from the input using a custom compare function the way [`ExternalSorter::sort_by`] does
+    /// cancelling the sorting by the provided token instead of the one set by
+    /// [`ExternalSorterBuilder::with_cancellation`]. A sorter can be reused this way after one of its sortings
+    /// is cancelled.
+    ///
+    /// # Arguments
+    /// * `input` - Input stream data to be fetched from
+    /// * `compare` - Function be be used to compare items
+    /// * `token` - Token the sorting can be cancelled with
+    #[allow(clippy::type_complexity)]
+    pub fn sort_by_cancellable<I, F>(
+        &self,
+        input: I,
+        compare: F,
+        token: &CancellationToken,
+    ) -> Result<SortedIterator<T, E, F, C>, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        catch_panic(|| self.sort_in_context(&self.new_context().cancellable(token), input, compare))
+    }
+
+    /// Sorts data from the input the way [`ExternalSorter::sort_by`] does within the sorting context.
+    #[allow(clippy::type_complexity)]
+    fn sort_in_context<I, F>(
+        &self,
+        ctx: &SortContext<T>,
+        input: I,
+        compare: F,
+    ) -> Result<SortedIterator<T, E, F, C>, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let input = self.tracked_input(ctx, input);
+
+        if let Some(persistence) = &self.persistence {
+            let external_chunks = self.create_chunks_persistent(ctx, persistence, input, &compare)?;
+            return self.merge_chunks(ctx, external_chunks, None, compare);
+        }
+
+        let (external_chunks, memory_chunk) = self.generate_chunks(ctx, input, &compare)?;
+
+        return self.merge_chunks(ctx, external_chunks, memory_chunk, compare);
+    }
+
+    /// Sorts data from the input using a fallible compare function.
+    /// Returns an iterator that can be used to get sorted data stream.
+    ///
+    /// Once the compare function fails the input is not read anymore, the items being sorted or merged are
+    /// considered equal and the error is returned as [`SortError::CompareError`] either by this method
+    /// as soon as the next chunk is about to be merged or by the returned iterator which stops right after it.
+    /// In the persistent mode the chunks dumped after the failure are not ordered, a failure returned
+    /// by this method removes them keeping the chunks persisted before it to be resumed from.
+    ///
+    /// # Arguments
+    /// * `input` - Input stream data to be fetched from
+    /// * `compare` - Function be be used to compare items
+    #[allow(clippy::type_complexity)]
+    pub fn try_sort_by<I, F, CE>(
+        &self,
+        input: I,
+        compare: F,
+    ) -> Result<
+        SortedIterator<T, E, impl Fn(&T, &T) -> Ordering, C>,
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Result<Ordering, CE> + Sync + Send,
+        CE: Error + Send + Sync + 'static,
+    {
+        let failure = Arc::new(CompareFailure::new());
+
+        let compare = {
+            let failure = Arc::clone(&failure);
+            move |a: &T, b: &T| {
+                if failure.is_set() {
+                    return Ordering::Equal;
+                }
+                return compare(a, b).unwrap_or_else(|err| {
+                    failure.set(Box::new(err));
+                    Ordering::Equal
+                });
+            }
+        };
+        let input = input.into_iter().take_while(|_| !failure.is_set());
+
+        let sorted = catch_panic(|| self.sort_in_context(&self.new_context().failable(&failure), input, compare));
+        if let (Err(SortError::CompareError(_)), Some(persistence)) = (&sorted, &self.persistence) {
+            persistence.discard_unsaved().map_err(SortError::IO)?;
+        }
+
+        return sorted;
+    }
+
+    /// Returns the run manifest saved by the latest persistent sorting, [`None`] if nothing is persisted
+    /// or the persistent mode is not enabled.
+    pub fn manifest(&self) -> Result<Option<Manifest>, SortError<C::SerializationError, C::DeserializationError, E>> {
+        return match &self.persistence {
+            Some(persistence) => persistence.read_manifest().map_err(SortError::IO),
+            None => Ok(None),
+        };
+    }
+
+    /// Removes the run manifest and the chunk files kept in the persistent directory, so the next sorting
+    /// starts from scratch. It should be called once the sorted data is consumed.
+    pub fn clear_persisted(&self) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>> {
+        if let Some(persistence) = &self.persistence {
+            persistence.clear().map_err(SortError::IO)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Sorts data from the input using a custom compare function keeping only the first `limit` items
+    /// of the sorted data stream.
+    /// Returns an iterator that can be used to get at most `limit` sorted items.
+    ///
+    /// Every filled buffer is sorted and truncated to `limit` items. If they take at most a half of a buffer they are
+    /// kept in memory and the disk is not touched at all, otherwise they are dumped as a chunk. Once the dumped chunks hold
+    /// twice as many items as `limit` they are merged into a single chunk holding the first `limit` of them.
+    /// Any later item that can't get into the first `limit` items is discarded right away. Finally the chunks are
+    /// merged into a single chunk holding the first `limit` items. Run generation strategy and pipelining
+    /// are not applied in this mode.
+    ///
+    /// # Arguments
+    /// * `input` - Input stream data to be fetched from
+    /// * `compare` - Function be be used to compare items
+    /// * `limit` - Maximum number of items to be returned
+    #[allow(clippy::type_complexity)]
+    pub fn sort_by_limit<I, F>(
+        &self,
+        input: I,
+        compare: F,
+        limit: usize,
+    ) -> Result<SortedIterator<T, E, F, C>, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        T: Clone,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        self.check_not_persistent("sort_by_limit")?;
+        if limit == 0 {
+            return Ok(self.sorted_in_memory(&self.new_context(), Vec::new()));
+        }
+
+        catch_panic(|| {
+            let ctx = self.new_context();
+            let input = self.tracked_input(&ctx, input);
+            let (external_chunks, memory_chunk) = self.create_chunks_limited(&ctx, input, &compare, limit)?;
+            self.check_cancelled(&ctx)?;
+
+            if external_chunks.is_empty() {
+                log::debug!("input top items selected in memory");
+                return Ok(self.sorted_in_memory(&ctx, memory_chunk.unwrap_or_default()));
+            }
+
+            let ((_, chunk_file), _) = self.merge_top_items(&ctx, external_chunks, memory_chunk, &compare, limit)?;
+
+            log::debug!("external sort preparation done");
+
+            let chunk = C::open(chunk_file, self.rw_buf_size).map_err(SortError::IO)?;
+            return Ok(self.sorted_iterator(
+                &ctx,
+                SortedItems::Merged(self.new_merger(&ctx, vec![MergeSource::Chunk(chunk)], compare)),
+            ));
+        })
+    }
+
+    /// Sorts data from the input using a custom compare function splitting the result into `partitions`
+    /// disjoint key ranges that can be merged concurrently.
+    /// Returns a list of iterators, one per key range, in ascending order of the ranges. Each iterator can be
+    /// consumed by a separate thread, chaining them yields the whole sorted data stream.
+    /// Every iterator holds its own clone of the compare function.
+    ///
+    /// Chunks are created and reduced to the merge fan-in the same way [`ExternalSorter::sort_by`] does.
+    /// Every chunk is sampled while it is written and the range splitters are picked from the samples
+    /// of all the chunks. The chunks are then split into range chunks concurrently on the sorter thread pool,
+    /// the last chunk kept in memory is split in memory. Every range merges at most the merge fan-in
+    /// chunks and the merge phase memory is shared by all the range chunks.
+    ///
+    /// # Arguments
+    /// * `input` - Input stream data to be fetched from
+    /// * `compare` - Function be be used to compare items
+    /// * `partitions` - Number of key ranges
+    ///
+    /// # Panics
+    /// Panics if `partitions` is 0.
+    #[allow(clippy::type_complexity)]
+    pub fn sort_by_partitioned<I, F>(
+        &self,
+        input: I,
+        compare: F,
+        partitions: usize,
+    ) -> Result<Vec<SortedIterator<T, E, F, C>>, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        Self: Sync,
+        T: Clone + Sync,
+        E: Send,
+        C::SerializationError: Send,
+        C::DeserializationError: Send,
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send + Clone,
+    {
+        assert!(partitions >= 1, "partitions number must be at least 1");
+        self.check_not_persistent("sort_by_partitioned")?;
+
+        catch_panic(|| {
+            let samples = ChunkSamples::new(partitions * SAMPLES_PER_PARTITION);
+            let ctx = self.new_context().sampled(&samples);
+            let input = self.tracked_input(&ctx, input);
+            let (mut external_chunks, mut memory_chunk) = self.generate_chunks(&ctx, input, &compare)?;
+            self.check_cancelled(&ctx)?;
+
+            let item_memory = Self::item_memory(&external_chunks);
+            self.reduce_chunks(&ctx, &mut external_chunks, self.merge_fanin(item_memory), &compare)?;
+
+            if let Some(items) = &memory_chunk {
+                samples.add_items(items);
+            }
+            let splitters = samples.splitters(partitions, &compare);
+            // items equal to a splitter belong to the upper range
+            let partition_of =
+                |item: &T| splitters.partition_point(|splitter| compare(splitter, item) != Ordering::Greater);
+
+            log::debug!("splitting chunks into {} key ranges ...", partitions);
+
+            let split_chunks = self.thread_pool.install(|| {
+                external_chunks
+                    .into_par_iter()
+                    .map(|(_, chunk_file)| self.split_chunk(&ctx, chunk_file, partition_of))
+                    .collect::<Result<Vec<_>, _>>()
+            })?;
+            // every range holds the range chunks in the order of the chunks to keep sorting stable
+            let mut ranges = Vec::from_iter((0..partitions).map(|_| Vec::with_capacity(split_chunks.len())));
+            for (partition, range_chunk) in split_chunks.into_iter().flatten() {
+                ranges[partition].push((0, range_chunk));
+            }
+
+            let mut memory_ranges = Vec::from_iter((0..partitions).map(|_| None));
+            if let Some(items) = memory_chunk.as_mut() {
+                for partition in (0..partitions).rev() {
+                    let range_items = items.split_off(items.partition_point(|item| partition_of(item) < partition));
+                    memory_ranges[partition] = Some(range_items);
+                }
+            }
+
+            log::debug!("external sort preparation done");
+
+            let read_buf_size = self.read_buf_size(ranges.iter().map(Vec::len).sum(), item_memory);
+            let mut sorted = Vec::with_capacity(partitions);
+            for (range, memory_range) in ranges.into_iter().zip(memory_ranges) {
+                sorted.push(self.open_chunks(&ctx, range, memory_range, compare.clone(), read_buf_size)?);
+            }
+
+            return Ok(sorted);
+        })
+    }
+
+    /// Reduces the number of the sorted chunks to the merge fan-in and creates a merger of the chunks
+    /// and the in-memory chunk.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn merge_chunks<F>(
+        &self,
+        ctx: &SortContext<T>,
+        mut external_chunks: Vec<(usize, ExternalChunkFile)>,
+        memory_chunk: Option<Vec<T>>,
+        compare: F,
+    ) -> Result<SortedIterator<T, E, F, C>, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        self.check_cancelled(ctx)?;
+        self.check_compare_failure(ctx)?;
+
+        if external_chunks.is_empty() {
+            log::debug!("input sorted in memory");
+            return Ok(self.sorted_in_memory(ctx, memory_chunk.unwrap_or_default()));
+        }
+
+        let item_memory = Self::item_memory(&external_chunks);
+        self.reduce_chunks(ctx, &mut external_chunks, self.merge_fanin(item_memory), &compare)?;
+
+        log::debug!("external sort preparation done");
+
+        let read_buf_size = self.read_buf_size(external_chunks.len(), item_memory);
+        return self.open_chunks(ctx, external_chunks, memory_chunk, compare, read_buf_size);
+    }
+
+    /// Creates a merger of the chunks and the in-memory chunk reading every chunk with the provided buffer size.
+    #[allow(clippy::type_complexity)]
+    fn open_chunks<F>(
+        &self,
+        ctx: &SortContext<T>,
+        external_chunks: Vec<(usize, ExternalChunkFile)>,
+        memory_chunk: Option<Vec<T>>,
+        compare: F,
+        read_buf_size: Option<usize>,
+    ) -> Result<SortedIterator<T, E, F, C>, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        if external_chunks.is_empty() {
+            return Ok(self.sorted_in_memory(ctx, memory_chunk.unwrap_or_default()));
+        }
+
+        let mut sources = Vec::with_capacity(external_chunks.len());
+        for (_, chunk_file) in external_chunks {
+            let chunk = C::open(chunk_file, read_buf_size).map_err(SortError::IO)?;
+            sources.push(match self.prefetch {
+                Some(prefetch) => MergeSource::Prefetched((prefetch.open)(
+                    chunk,
+                    Arc::clone(&self.thread_pool),
+                    prefetch.batch_size,
+                    prefetch.queue_size,
+                )),
+                None => MergeSource::Chunk(chunk),
+            });
+        }
+        // the last chunk holds the latest input items so it is merged last to keep sorting stable
+        if let Some(memory_chunk) = memory_chunk {
+            sources.push(MergeSource::Memory(memory_chunk.into_iter()));
+        }
+
+        let merger = self.new_merger(ctx, sources, compare);
+        if let Some(combiner) = &self.combiner {
+            let combine = (combiner.boxed)(&combiner.combine);
+            let combined = Combined::new(merger, combine);
+            return Ok(self.sorted_iterator(ctx, SortedItems::Combined(combined)));
+        }
+
+        return Ok(self.sorted_iterator(ctx, SortedItems::Merged(merger)));
+    }
+
+    /// Performs intermediate merges until the number of chunks does not exceed the merge fan-in.
+    fn reduce_chunks<F>(
+        &self,
+        ctx: &SortContext<T>,
+        chunks: &mut Vec<(usize, ExternalChunkFile)>,
+        fanin: Option<usize>,
+        compare: F,
+    ) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        if let Some(fanin) = fanin {
+            while chunks.len() > fanin {
+                // merge the smallest trailing chunks so that exactly `fanin` chunks remain if possible
+                let merge_count = usize::min(fanin, chunks.len() - fanin + 1);
+                self.merge_tail(ctx, chunks, merge_count, &compare)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Creates sorted chunks from the input using the configured run generation strategy and pipelining.
+    #[allow(clippy::type_complexity)]
+    fn generate_chunks<I, F>(
+        &self,
+        ctx: &SortContext<T>,
+        input: I,
+        compare: F,
+    ) -> Result<
+        (Vec<(usize, ExternalChunkFile)>, Option<Vec<T>>),
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        return match (self.run_generation, self.pipeline) {
+            (RunGeneration::NaturalRuns, _) => self.create_chunks_natural(ctx, input, compare),
+            (RunGeneration::ReplacementSelection, _) => self.create_chunks_replacement_selection(ctx, input, compare),
+            (RunGeneration::SortBuffer, Some(pipeline)) => self.create_chunks_pipelined(ctx, input, compare, pipeline),
+            (RunGeneration::SortBuffer, None) => self.create_chunks(ctx, input, compare),
+        };
+    }
+
+    /// Creates sorted chunks from the input. Every chunk is paired with its merge level,
+    /// levels are non-increasing from the first chunk to the last one.
+    #[allow(clippy::type_complexity)]
+    fn create_chunks<I, F>(
+        &self,
+        ctx: &SortContext<T>,
+        input: I,
+        compare: F,
+    ) -> Result<
+        (Vec<(usize, ExternalChunkFile)>, Option<Vec<T>>),
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let mut sink = self.new_sink(ctx.clone(), compare);
+
+        for item in input.into_iter() {
+            match item {
+                Ok(item) => sink.push_item(item)?,
+                Err(err) => return Err(SortError::InputError(err)),
+            }
+        }
+
+        return Ok(sink.into_chunks());
+    }
+
+    /// Creates sorted chunks in the persistent directory continuing the persisted sorting if there is one.
+    /// The manifest is saved after every chunk, the last chunk is dumped as well and the chunks are reduced
+    /// to the merge fan-in before the input is marked complete so that only the final merge is left.
+    #[allow(clippy::type_complexity)]
+    fn create_chunks_persistent<I, F>(
+        &self,
+        ctx: &SortContext<T>,
+        persistence: &Persistence,
+        input: I,
+        compare: F,
+    ) -> Result<Vec<(usize, ExternalChunkFile)>, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let ctx = &ctx.persistent(persistence);
+        let (mut chunks, manifest) = persistence.resume().map_err(SortError::IO)?;
+        let mut input_offset = manifest.input_offset;
+
+        if manifest.input_complete {
+            log::info!("resuming merge of {} persisted chunks", chunks.len());
+        } else {
+            if input_offset > 0 {
+                log::info!(
+                    "resuming sorting of {} persisted chunks at input offset {}",
+                    chunks.len(),
+                    input_offset
+                );
+            }
+
+            let mut input = input.into_iter();
+            let mut skipped = 0;
+            for item in input.by_ref().take(usize::try_from(input_offset).unwrap_or(usize::MAX)) {
+                item.map_err(SortError::InputError)?;
+                skipped += 1;
+            }
+            if skipped < input_offset {
+                return Err(SortError::IO(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("input is shorter than the persisted input offset {}", input_offset),
+                )));
+            }
+
+            let mut buffer = self.buffer_builder.build();
+            for item in input {
+                buffer.push(item.map_err(SortError::InputError)?);
+
+                if buffer.is_full() {
+                    input_offset += buffer.len() as u64;
+                    let buffer = mem::replace(&mut buffer, self.buffer_builder.build());
+                    let chunk = self.create_chunk(ctx, buffer, &compare)?;
+                    self.push_chunk(ctx, &mut chunks, chunk, &compare)?;
+                    // a chunk merged after the compare function failed is not ordered, so it is not persisted
+                    self.check_compare_failure(ctx)?;
+                    persistence.save(&chunks, input_offset, false).map_err(SortError::IO)?;
+                }
+            }
+            // the input stops early once the sorting is cancelled, so it must not be marked complete
+            self.check_cancelled(ctx)?;
+
+            if !buffer.is_empty() {
+                input_offset += buffer.len() as u64;
+                let chunk = self.create_chunk(ctx, buffer, &compare)?;
+                self.push_chunk(ctx, &mut chunks, chunk, &compare)?;
+            }
+        }
+
+        let fanin = self.merge_fanin(Self::item_memory(&chunks));
+        self.reduce_chunks(ctx, &mut chunks, fanin, &compare)?;
+        self.check_compare_failure(ctx)?;
+        persistence.save(&chunks, input_offset, true).map_err(SortError::IO)?;
+
+        return Ok(chunks);
+    }
+
+    /// Creates sorted chunks holding at most `limit` items each. Filled buffers are sorted and truncated,
+    /// the remaining items are kept in a new buffer if they take at most a half of it, otherwise they are dumped,
+    /// so every buffer sorting takes at least as many new items as it keeps.
+    /// Once the dumped chunks hold `2 * limit` items they are merged into a single chunk holding `limit` items.
+    /// Once `limit` items are selected the items greater than the last of them (or equal to it unless a combiner
+    /// is set) are not taken from the input since they can't get into the first `limit` items of the sorted stream.
+    #[allow(clippy::type_complexity)]
+    fn create_chunks_limited<I, F>(
+        &self,
+        ctx: &SortContext<T>,
+        input: I,
+        compare: F,
+        limit: usize,
+    ) -> Result<
+        (Vec<(usize, ExternalChunkFile)>, Option<Vec<T>>),
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        T: Clone,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let mut chunk_buf = self.buffer_builder.build();
+        let mut external_chunks = Vec::new();
+        let mut threshold: Option<T> = None;
+        // items equal to the last selected one are still to be combined with it
+        let min_skipped = match self.combiner {
+            Some(_) => Ordering::Greater,
+            None => Ordering::Equal,
+        };
+
+        for item in input.into_iter() {
+            match item {
+                Ok(item)
+                    if threshold
+                        .as_ref()
+                        .is_some_and(|last| compare(&item, last) >= min_skipped) =>
+                {
+                    continue
+                }
+                Ok(item) => chunk_buf.push(item),
+                Err(err) => return Err(SortError::InputError(err)),
+            }
+
+            if !chunk_buf.is_full() {
+                continue;
+            }
+
+            // the buffer may be limited by memory so its capacity is the number of items it took
+            let capacity = chunk_buf.len();
+            let mut top_items = self.sort_in_memory(ctx, chunk_buf, &compare);
+            top_items.truncate(limit);
+            if top_items.len() == limit {
+                threshold = top_items.last().cloned();
+            }
+
+            chunk_buf = self.buffer_builder.build();
+            // the top items are kept in the buffer only if they leave room for at least as many new items,
+            // otherwise the buffer would be sorted again after a few new items
+            let chunk = if top_items.len() * 2 > capacity {
+                log::debug!("top items take more than a half of a buffer, dumping them ...");
+                self.build_chunk(ctx, top_items)?
+            } else {
+                for item in top_items {
+                    chunk_buf.push(item);
+                }
+                if !chunk_buf.is_full() {
+                    continue;
+                }
+                log::debug!("top items do not fit in a buffer, dumping them ...");
+                self.build_chunk(ctx, mem::replace(&mut chunk_buf, self.buffer_builder.build()))?
+            };
+            self.push_chunk(ctx, &mut external_chunks, chunk, &compare)?;
+
+            let dumped: u64 = external_chunks.iter().map(|(_, chunk)| chunk.items()).sum();
+            if dumped >= (limit as u64).saturating_mul(2) {
+                // the last of the first `limit` dumped items is a threshold for the following ones
+                let chunks = mem::take(&mut external_chunks);
+                let (chunk, last) = self.merge_top_items(ctx, chunks, None, &compare, limit)?;
+                external_chunks.push(chunk);
+                threshold = last.or(threshold);
+            }
+        }
+
+        let memory_chunk = (!chunk_buf.is_empty()).then(|| {
+            let mut top_items = self.sort_in_memory(ctx, chunk_buf, &compare);
+            top_items.truncate(limit);
+            top_items
+        });
+
+        return Ok((external_chunks, memory_chunk));
+    }
+
+    /// Merges the chunks and the in-memory chunk into a single chunk holding the first `limit` items.
+    /// Returns the chunk paired with its merge level and the last of the items if the chunk holds `limit` items.
+    #[allow(clippy::type_complexity)]
+    fn merge_top_items<F>(
+        &self,
+        ctx: &SortContext<T>,
+        mut chunks: Vec<(usize, ExternalChunkFile)>,
+        memory_chunk: Option<Vec<T>>,
+        compare: F,
+        limit: usize,
+    ) -> Result<((usize, ExternalChunkFile), Option<T>), SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        T: Clone,
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let item_memory = Self::item_memory(&chunks);
+        let sources_number = chunks.len() + usize::from(memory_chunk.is_some());
+        if let Some(fanin) = self.merge_fanin(item_memory) {
+            // leave room for the in-memory chunk merged along with the dumped ones
+            let fanin = usize::max(2, fanin - usize::from(memory_chunk.is_some()));
+            while chunks.len() > fanin {
+                let merge_count = usize::min(fanin, chunks.len() - fanin + 1);
+                self.merge_tail(ctx, &mut chunks, merge_count, &compare)?;
+            }
+        }
+        let level = chunks.iter().map(|(level, _)| *level).max().unwrap_or(0) + 1;
+
+        log::debug!("merging {} chunks into top {} items ...", sources_number, limit);
+
+        let read_buf_size = self.read_buf_size(chunks.len() + usize::from(memory_chunk.is_some()), item_memory);
+        let mut sources = Vec::with_capacity(chunks.len() + 1);
+        for (_, chunk_file) in chunks {
+            sources.push(MergeSource::Chunk(
+                C::open(chunk_file, read_buf_size).map_err(SortError::IO)?,
+            ));
+        }
+        if let Some(memory_chunk) = memory_chunk {
+            sources.push(MergeSource::Memory(memory_chunk.into_iter()));
+        }
+
+        let mut merge_error = None;
+        let mut last = None;
+        let merged = self
+            .new_merger(ctx, sources, &compare)
+            .map_while(|item| item.map_err(|err| merge_error = Some(err)).ok());
+        let merged = combine_items(merged, &compare, self.combine_fn())
+            .take(limit)
+            .enumerate()
+            .map(|(idx, item)| {
+                if idx + 1 == limit {
+                    last = Some(item.clone());
+                }
+                return item;
+            });
+        let chunk_file = self.build_chunk(ctx, merged)?;
+        self.check_cancelled(ctx)?;
+
+        if let Some(err) = merge_error {
+            return Err(SortError::DeserializationError(err));
+        }
+        ctx.progress.on_intermediate_merge();
+
+        return Ok(((level, chunk_file), last));
+    }
+
+    /// Creates sorted chunks from the input the same way as [`ExternalSorter::create_chunks`] does
+    /// but sorts and dumps filled buffers on the thread pool while the input is being read.
+    #[allow(clippy::type_complexity)]
+    fn create_chunks_pipelined<I, F>(
+        &self,
+        ctx: &SortContext<T>,
+        input: I,
+        compare: F,
+        pipeline: Pipeline<C::SerializationError>,
+    ) -> Result<
+        (Vec<(usize, ExternalChunkFile)>, Option<Vec<T>>),
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let depth = pipeline.depth;
+        self.thread_pool.in_place_scope(|scope| {
+            let mut chunk_buf = self.buffer_builder.build();
+            let mut external_chunks = Vec::new();
+            // chunks are collected in the order the buffers were filled in to keep sorting stable
+            let mut pending_chunks = VecDeque::with_capacity(depth);
+
+            for item in input.into_iter() {
+                match item {
+                    Ok(item) => chunk_buf.push(item),
+                    Err(err) => return Err(SortError::InputError(err)),
+                }
+
+                if chunk_buf.is_full() {
+                    if pending_chunks.len() >= depth {
+                        let chunk = Self::wait_chunk(pending_chunks.pop_front().expect("queue is not empty"))?;
+                        self.push_chunk(ctx, &mut external_chunks, chunk, &compare)?;
+                    }
+                    self.check_cancelled(ctx)?;
+                    pending_chunks.push_back(self.spawn_chunk(ctx, scope, pipeline, chunk_buf, &compare));
+                    chunk_buf = self.buffer_builder.build();
+                }
+            }
+
+            // the last buffer is sorted while the pending chunks are being dumped
+            let memory_chunk = (!chunk_buf.is_empty()).then(|| self.sort_in_memory(ctx, chunk_buf, &compare));
+
+            for pending_chunk in pending_chunks {
+                let chunk = Self::wait_chunk(pending_chunk)?;
+                self.push_chunk(ctx, &mut external_chunks, chunk, &compare)?;
+            }
+
+            return Ok((external_chunks, memory_chunk));
+        })
+    }
+
+    /// Creates sorted chunks from the input using replacement selection.
+    /// If the input fits in a single buffer it is sorted the same way [`ExternalSorter::create_chunks`] does.
+    /// The last run is returned without being dumped.
+    #[allow(clippy::type_complexity)]
+    fn create_chunks_replacement_selection<I, F>(
+        &self,
+        ctx: &SortContext<T>,
+        input: I,
+        compare: F,
+    ) -> Result<
+        (Vec<(usize, ExternalChunkFile)>, Option<Vec<T>>),
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let mut input = input.into_iter();
+        let mut chunk_buf = self.buffer_builder.build();
+        let mut external_chunks = Vec::new();
+
+        // the first filled buffer determines the selection heap size
+        while !chunk_buf.is_full() {
+            match input.next() {
+                Some(Ok(item)) => chunk_buf.push(item),
+                Some(Err(err)) => return Err(SortError::InputError(err)),
+                None => {
+                    let memory_chunk = (!chunk_buf.is_empty()).then(|| self.sort_in_memory(ctx, chunk_buf, &compare));
+                    return Ok((external_chunks, memory_chunk));
+                }
+            }
+        }
+
+        log::debug!("selection heap size: {}", chunk_buf.len());
+        let mut selection = ReplacementSelection::new(chunk_buf, &compare);
+        let mut input_error = None;
+        let mut input_exhausted = false;
+
+        while let Some(run) = selection.run() {
+            if input_exhausted {
+                // all the items left in the heap belong to the last run
+                log::debug!("keeping the last run in memory (run: {})", run);
+                let memory_chunk = Vec::from_iter(combine_items(
+                    iter::from_fn(|| selection.replace(None)),
+                    &compare,
+                    self.combine_fn(),
+                ));
+                return Ok((external_chunks, Some(memory_chunk)));
+            }
+
+            log::debug!("saving chunk data (run: {}) ...", run);
+
+            let items = iter::from_fn(|| {
+                if selection.run()? != run {
+                    return None;
+                }
+                let next = match (&input_error, input_exhausted) {
+                    (None, false) => match input.next() {
+                        Some(Ok(item)) => Some(item),
+                        Some(Err(err)) => {
+                            input_error = Some(err);
+                            None
+                        }
+                        None => {
+                            input_exhausted = true;
+                            None
+                        }
+                    },
+                    _ => None,
+                };
+                return selection.replace(next);
+            });
+            let chunk = self.build_run(ctx, combine_items(items, &compare, self.combine_fn()))?;
+
+            if let Some(err) = input_error {
+                return Err(SortError::InputError(err));
+            }
+            self.push_chunk(ctx, &mut external_chunks, chunk, &compare)?;
+        }
+
+        return Ok((external_chunks, None));
+    }
+
+    /// Creates sorted chunks from the input detecting natural runs. The ascending run the buffer items end with
+    /// is tracked item by item. If it takes at least half of a filled buffer it is not sorted but extended
+    /// by the following ordered input items, the items preceding it are sorted and dumped separately.
+    /// The last partially filled buffer is returned without being dumped, if its items are ordered
+    /// it is not sorted either.
+    #[allow(clippy::type_complexity)]
+    fn create_chunks_natural<I, F>(
+        &self,
+        ctx: &SortContext<T>,
+        input: I,
+        compare: F,
+    ) -> Result<
+        (Vec<(usize, ExternalChunkFile)>, Option<Vec<T>>),
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let mut input = input.into_iter();
+        let mut chunk_buf = self.buffer_builder.build();
+        let mut external_chunks = Vec::new();
+        // the latest input item, it is kept out of the buffer to be compared with the next one
+        let mut pending: Option<T> = None;
+        // index of the buffer item the current ascending run starts at
+        let mut run_start = 0;
+
+        loop {
+            let item = match input.next() {
+                Some(Ok(item)) => item,
+                Some(Err(err)) => return Err(SortError::InputError(err)),
+                None => break,
+            };
+
+            if let Some(last) = pending.take() {
+                if compare(&last, &item) == Ordering::Greater {
+                    run_start = chunk_buf.len() + 1;
+                }
+                chunk_buf.push(last);
+            }
+            pending = Some(item);
+
+            if chunk_buf.is_full() {
+                let run_len = chunk_buf.len() - run_start;
+                if run_len * 2 >= chunk_buf.len() {
+                    // the current run takes most of the buffer, it is continued by the following input items
+                    let (chunks, next_item) =
+                        self.create_natural_chunks(ctx, chunk_buf, run_start, pending.take(), &mut input, &compare)?;
+                    for chunk in chunks {
+                        self.push_chunk(ctx, &mut external_chunks, chunk, &compare)?;
+                    }
+                    pending = next_item;
+                } else {
+                    let chunk = self.create_chunk(ctx, chunk_buf, &compare)?;
+                    self.push_chunk(ctx, &mut external_chunks, chunk, &compare)?;
+                }
+
+                chunk_buf = self.buffer_builder.build();
+                run_start = 0;
+            }
+        }
+
+        if let Some(last) = pending {
+            chunk_buf.push(last);
+        }
+        if chunk_buf.is_empty() {
+            return Ok((external_chunks, None));
+        }
+
+        let memory_chunk = if run_start == 0 {
+            if external_chunks.is_empty() {
+                log::debug!("input is already sorted");
+            }
+            Vec::from_iter(combine_items(chunk_buf, &compare, self.combine_fn()))
+        } else {
+            self.sort_in_memory(ctx, chunk_buf, &compare)
+        };
+
+        return Ok((external_chunks, Some(memory_chunk)));
+    }
+
+    /// Dumps the buffer items preceding the ascending run starting at `run_start` as a sorted chunk,
+    /// then dumps the run followed by the `last` item and the input items as long as they keep the order.
+    /// Returns the created chunks and the input item interrupted the order if any.
+    #[allow(clippy::type_complexity)]
+    fn create_natural_chunks<F>(
+        &self,
+        ctx: &SortContext<T>,
+        mut buffer: impl ChunkBuffer<T>,
+        run_start: usize,
+        last: Option<T>,
+        input: &mut impl Iterator<Item = Result<T, E>>,
+        compare: F,
+    ) -> Result<(Vec<ExternalChunkFile>, Option<T>), SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        let mut chunks = Vec::with_capacity(2);
+
+        if run_start > 0 {
+            log::debug!("sorting chunk data preceding natural run ...");
+            let unstable = self.unstable;
+            let started = Instant::now();
+            self.thread_pool.install(|| {
+                sort_buffer(&mut buffer.as_parallel_slice_mut()[..run_start], &compare, unstable);
+            });
+            ctx.progress.on_buffer_sorted(started.elapsed());
+        }
+
+        let mut buffered = buffer.into_iter();
+        if run_start > 0 {
+            let items = buffered.by_ref().take(run_start);
+            chunks.push(self.build_run(ctx, combine_items(items, &compare, self.combine_fn()))?);
+        }
+
+        log::debug!("saving natural run chunk data ...");
+
+        let mut run = buffered.chain(last);
+        let mut current = run.next();
+        let mut next_item = None;
+        let mut input_error = None;
+        let mut run_ended = false;
+
+        let items = iter::from_fn(|| {
+            let item = current.take()?;
+            if let Some(buffered) = run.next() {
+                current = Some(buffered);
+            } else if !run_ended {
+                match input.next() {
+                    Some(Ok(next)) if compare(&item, &next) != Ordering::Greater => current = Some(next),
+                    Some(Ok(next)) => {
+                        next_item = Some(next);
+                        run_ended = true;
+                    }
+                    Some(Err(err)) => {
+                        input_error = Some(err);
+                        run_ended = true;
+                    }
+                    None => run_ended = true,
+                }
+            }
+            return Some(item);
+        });
+        chunks.push(self.build_run(ctx, combine_items(items, &compare, self.combine_fn()))?);
+
+        if let Some(err) = input_error {
+            return Err(SortError::InputError(err));
+        }
+
+        return Ok((chunks, next_item));
+    }
+
+    /// Sorts and dumps the buffer on the thread pool. The created chunk is sent to the returned receiver.
+    fn spawn_chunk<'scope, F>(
+        &'scope self,
+        ctx: &SortContext<'scope, T>,
+        scope: &rayon::Scope<'scope>,
+        pipeline: Pipeline<C::SerializationError>,
+        mut buffer: B::Buffer,
+        compare: F,
+    ) -> ChunkReceiver<C::SerializationError>
+    where
+        F: Fn(&T, &T) -> Ordering + Sync + Send + 'scope,
+    {
+        let tmp_dir = &self.tmp_dir;
+        let rw_buf_size = self.rw_buf_size;
+        let combiner = self.combine_fn();
+        let unstable = self.unstable;
+        let progress = Arc::clone(&ctx.progress);
+        let samples = ctx.samples;
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "create_chunk",
+            chunk = progress.next_chunk_index(),
+            items = buffer.len()
+        );
+
+        let job = Box::new(move || {
+            #[cfg(feature = "tracing")]
+            let _span = span.entered();
+
+            log::debug!("sorting chunk data ...");
+            let started = Instant::now();
+            sort_buffer(buffer.as_parallel_slice_mut(), &compare, unstable);
+            progress.on_buffer_sorted(started.elapsed());
+
+            log::debug!("saving chunk data");
+            #[cfg(feature = "tracing")]
+            let span = chunk_span().entered();
+            let started = Instant::now();
+            let items = combine_items(buffer, &compare, combiner);
+            let result = match samples {
+                Some(samples) => C::build_file(tmp_dir, samples.sample(items), rw_buf_size),
+                None => C::build_file(tmp_dir, items, rw_buf_size),
+            };
+            if let Ok(chunk_file) = &result {
+                progress.on_chunk_written(chunk_file.len(), started.elapsed());
+                #[cfg(feature = "tracing")]
+                record_chunk(&span, chunk_file);
+            }
+            return result;
+        });
+
+        return (pipeline.spawn)(scope, job);
+    }
+
+    fn wait_chunk(
+        receiver: ChunkReceiver<C::SerializationError>,
+    ) -> Result<ExternalChunkFile, SortError<C::SerializationError, C::DeserializationError, E>> {
+        // the sender is dropped without sending a result only if the job has panicked,
+        // the panic itself is propagated by the scope the job is spawned in
+        let result = receiver
+            .recv()
+            .map_err(|_| SortError::Panicked("chunk creation job terminated unexpectedly".to_string()))?;
+        return result.map_err(Self::map_chunk_error);
+    }
+
+    pub(crate) fn create_chunk<F>(
+        &self,
+        ctx: &SortContext<T>,
+        mut buffer: impl ChunkBuffer<T>,
+        compare: F,
+    ) -> Result<ExternalChunkFile, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        self.check_cancelled(ctx)?;
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "create_chunk",
+            chunk = ctx.progress.next_chunk_index(),
+            items = buffer.len(),
+        )
+        .entered();
+
+        log::debug!("sorting chunk data ...");
+        let unstable = self.unstable;
+        let started = Instant::now();
+        self.thread_pool.install(|| {
+            sort_buffer(buffer.as_parallel_slice_mut(), &compare, unstable);
+        });
+        ctx.progress.on_buffer_sorted(started.elapsed());
+
+        log::debug!("saving chunk data");
+        return self.build_run(ctx, combine_items(buffer, &compare, self.combine_fn()));
+    }
+
+    /// Splits the sorted chunk into consecutive key ranges. Only the chunk being split and the range chunk
+    /// being written are open at a time. Returns the range chunks paired with their range indices.
+    #[allow(clippy::type_complexity)]
+    fn split_chunk<P>(
+        &self,
+        ctx: &SortContext<T>,
+        chunk_file: ExternalChunkFile,
+        partition_of: P,
+    ) -> Result<Vec<(usize, ExternalChunkFile)>, SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        P: Fn(&T) -> usize,
+    {
+        let chunk = C::open(chunk_file, self.rw_buf_size).map_err(SortError::IO)?;
+        let mut read_error = None;
+        let mut range_chunks = Vec::new();
+        {
+            let mut items = chunk
+                .map_while(|item| item.map_err(|err| read_error = Some(err)).ok())
+                .peekable();
+            while let Some(partition) = items.peek().map(&partition_of) {
+                let range_items = iter::from_fn(|| items.next_if(|item| partition_of(item) == partition));
+                range_chunks.push((partition, self.build_chunk(ctx, range_items)?));
+            }
+        }
+
+        if let Some(err) = read_error {
+            return Err(SortError::DeserializationError(err));
+        }
+
+        return Ok(range_chunks);
+    }
+
+    /// Sorts the buffer returning the sorted items.
+    pub(crate) fn sort_in_memory<F>(&self, ctx: &SortContext<T>, mut buffer: impl ChunkBuffer<T>, compare: F) -> Vec<T>
+    where
+        F: Fn(&T, &T) -> Ordering + Sync + Send,
+    {
+        log::debug!("sorting chunk data in memory ...");
+        let unstable = self.unstable;
+        let started = Instant::now();
+        self.thread_pool.install(|| {
+            sort_buffer(buffer.as_parallel_slice_mut(), &compare, unstable);
+        });
+        ctx.progress.on_buffer_sorted(started.elapsed());
+
+        return Vec::from_iter(combine_items(buffer, &compare, self.combine_fn()));
+    }
+
+    /// Adds a new chunk to the chunk list merging the trailing chunks of the same level
+    /// every time their number reaches the merge fan-in, which keeps the number of open chunks bounded.
+    pub(crate) fn push_chunk<F>(
+        &self,
+        ctx: &SortContext<T>,
+        chunks: &mut Vec<(usize, ExternalChunkFile)>,
+        chunk: ExternalChunkFile,
+        compare: F,
+    ) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        self.check_compare_failure(ctx)?;
+        chunks.push((0, chunk));
+
+        if let Some(fanin) = self.merge_fanin(Self::item_memory(chunks)) {
+            while chunks.len() >= fanin && chunks[chunks.len() - fanin].0 == chunks[chunks.len() - 1].0 {
+                self.merge_tail(ctx, chunks, fanin, &compare)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Merges `count` trailing chunks into a single one. Only adjacent chunks are merged to keep sorting stable.
+    fn merge_tail<F>(
+        &self,
+        ctx: &SortContext<T>,
+        chunks: &mut Vec<(usize, ExternalChunkFile)>,
+        count: usize,
+        compare: F,
+    ) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        self.check_compare_failure(ctx)?;
+        let tail = chunks.split_off(chunks.len() - count);
+        let level = tail.iter().map(|(level, _)| *level).max().unwrap_or(0) + 1;
+
+        if let Some(persistence) = ctx.persistence {
+            for path in tail.iter().filter_map(|(_, chunk_file)| chunk_file.path()) {
+                persistence.retire(path);
+            }
+        }
+
+        log::debug!("merging {} chunks (level: {}) ...", tail.len(), level);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("merge_chunks", chunks = tail.len(), level).entered();
+
+        let read_buf_size = self.read_buf_size(tail.len(), Self::item_memory(&tail));
+        let mut sources = Vec::with_capacity(tail.len());
+        for (_, chunk_file) in tail {
+            sources.push(C::open(chunk_file, read_buf_size).map_err(SortError::IO)?);
+        }
+
+        let mut merge_error = None;
+        let merged = self
+            .new_merger(ctx, sources, &compare)
+            .map_while(|item| item.map_err(|err| merge_error = Some(err)).ok());
+        let chunk = self.build_chunk(ctx, combine_items(merged, &compare, self.combine_fn()))?;
+        self.check_cancelled(ctx)?;
+
+        if let Some(err) = merge_error {
+            return Err(SortError::DeserializationError(err));
+        }
+        ctx.progress.on_intermediate_merge();
+        chunks.push((level, chunk));
+
+        return Ok(());
+    }
+
+    /// Estimates the memory taken by an item of the chunks. Besides the item itself every item is assumed to own
+    /// about as much heap memory as it takes serialized to the chunk files.
+    fn item_memory(chunks: &[(usize, ExternalChunkFile)]) -> u64 {
+        let bytes: u64 = chunks.iter().map(|(_, chunk)| chunk.len()).sum();
+        let items: u64 = chunks.iter().map(|(_, chunk)| chunk.items()).sum();
+
+        return mem::size_of::<T>() as u64 + bytes.checked_div(items).unwrap_or(0);
+    }
+
+    /// Returns the memory the merge phase may use or [`None`] if the memory is not limited.
+    /// Chunk buffers and chunk file write buffers are excluded from the budget since they may be
+    /// in use while intermediate merges are performed.
+    fn merge_memory(&self, item_memory: u64) -> Option<u64> {
+        let budget = self.memory_budget?;
+
+        return Some(budget.saturating_sub(self.buffers_memory(item_memory)));
+    }
+
+    /// Returns the memory taken by the chunk buffers filled, sorted and dumped concurrently including
+    /// the stable sorting scratch space and the chunk file write buffers. Buffers limited by items count
+    /// are assumed to take `item_memory` bytes per item, unlimited buffers take all the memory.
+    fn buffers_memory(&self, item_memory: u64) -> u64 {
+        let concurrent_buffers = self.pipeline.map_or(0, |pipeline| pipeline.depth) as u64 + 1;
+        let limits = (self.buffer_builder.mem_limit(), self.buffer_builder.items_limit());
+        let (buffer_memory, scratch_memory) = match limits {
+            // an item takes at least its size in the buffer so the scratch space does not exceed the buffer
+            (Some(mem_limit), _) => (mem_limit, mem_limit),
+            (None, Some(items_limit)) => (
+                (items_limit as u64).saturating_mul(item_memory),
+                (items_limit as u64).saturating_mul(mem::size_of::<T>() as u64),
+            ),
+            (None, None) => return u64::MAX,
+        };
+        // stable sorting allocates a copy of the sorted items
+        let buffer_memory = match self.unstable {
+            true => buffer_memory,
+            false => buffer_memory.saturating_add(scratch_memory),
+        };
+        let buffers_memory = buffer_memory.saturating_mul(concurrent_buffers);
+        // every buffer being dumped and the chunk being merged into have their own write buffer
+        let write_buffers_memory = self.rw_buf_size.unwrap_or(DEFAULT_BUF_SIZE) as u64 * (concurrent_buffers + 1);
+
+        return buffers_memory.saturating_add(write_buffers_memory);
+    }
+
+    /// Returns the memory taken by every merged chunk besides its read buffer:
+    /// the item held by the merger and the items prefetched in advance.
+    fn merge_source_memory(&self, item_memory: u64) -> u64 {
+        let prefetched_items = match self.prefetch {
+            Some(prefetch) => prefetch.batch_size * (prefetch.queue_size + 1),
+            None => 0,
+        };
+
+        return item_memory.saturating_mul(prefetched_items as u64 + 1);
+    }
+
+    /// Returns the maximum number of chunks merged at once limited either explicitly or by the memory budget.
+    fn merge_fanin(&self, item_memory: u64) -> Option<usize> {
+        let budget_fanin = self.merge_memory(item_memory).map(|memory| {
+            let fanin = memory / (MIN_READ_BUF_SIZE as u64).saturating_add(self.merge_source_memory(item_memory));
+            usize::max(2, usize::try_from(fanin).unwrap_or(usize::MAX))
+        });
+
+        return match (self.max_merge_fanin, budget_fanin) {
+            (Some(fanin), Some(budget_fanin)) => Some(usize::min(fanin, budget_fanin)),
+            (fanin, budget_fanin) => fanin.or(budget_fanin),
+        };
+    }
+
+    /// Returns the read buffer size of every chunk when `count` chunks are merged at once.
+    fn read_buf_size(&self, count: usize, item_memory: u64) -> Option<usize> {
+        let memory = match self.merge_memory(item_memory) {
+            Some(memory) => memory,
+            None => return self.rw_buf_size,
+        };
+        let buf_size = (memory / count.max(1) as u64).saturating_sub(self.merge_source_memory(item_memory));
+
+        return Some(usize::try_from(buf_size).unwrap_or(usize::MAX).max(MIN_READ_BUF_SIZE));
+    }
+
+    /// Wraps the sorted items into an iterator reporting the sorting cancellation and the merge progress.
+    fn sorted_iterator<F>(&self, ctx: &SortContext<T>, items: SortedItems<T, F, C>) -> SortedIterator<T, E, F, C>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        return SortedIterator::new(
+            items,
+            ctx.cancellation.clone(),
+            ctx.compare_failure.clone(),
+            Arc::clone(&ctx.progress),
+        );
+    }
+
+    /// Returns an iterator over the items sorted in memory.
+    fn sorted_in_memory<F>(&self, ctx: &SortContext<T>, items: Vec<T>) -> SortedIterator<T, E, F, C>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        return self.sorted_iterator(ctx, SortedItems::Memory(items.into_iter()));
+    }
+
+    /// Creates a merger of the configured kind and stability.
+    fn new_merger<S, F>(
+        &self,
+        ctx: &SortContext<T>,
+        sources: impl IntoIterator<Item = S>,
+        compare: F,
+    ) -> Merger<T, C::DeserializationError, F, S>
+    where
+        S: IntoIterator<Item = Result<T, C::DeserializationError>>,
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let merger = Merger::new(self.merger_kind, sources, compare).with_stability(!self.unstable);
+        return match &ctx.cancellation {
+            Some(token) => merger.with_cancellation(token.clone()),
+            None => merger,
+        };
+    }
+
+    /// Counts the input items read and stops reading the input once the sorting is cancelled.
+    fn tracked_input<I>(&self, ctx: &SortContext<T>, input: I) -> impl Iterator<Item = Result<T, E>>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+    {
+        let cancellation = ctx.cancellation.clone();
+        let progress = Arc::clone(&ctx.progress);
+        input
+            .into_iter()
+            .take_while(move |_| !cancellation.as_ref().is_some_and(CancellationToken::is_cancelled))
+            .inspect(move |_| progress.on_item_read())
+    }
+
+    /// Creates the state of a new sorting reporting its progress to the observer.
+    /// The chunks of the sorting are temporary unless the context is made persistent.
+    pub(crate) fn new_context<'a>(&self) -> SortContext<'a, T> {
+        return SortContext {
+            progress: Arc::new(Progress::new(self.progress_observer.clone())),
+            persistence: None,
+            samples: None,
+            compare_failure: None,
+            cancellation: self.cancellation.clone(),
+        };
+    }
+
+    /// Returns the function combining equal items if it is set.
+    fn combine_fn(&self) -> Option<&CombineFn<T>> {
+        self.combiner.as_ref().map(|combiner| &*combiner.combine)
+    }
+
+    pub(crate) fn check_cancelled(
+        &self,
+        ctx: &SortContext<T>,
+    ) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>> {
+        return match ctx.is_cancelled() {
+            true => Err(SortError::Cancelled),
+            false => Ok(()),
+        };
+    }
+
+    /// Returns an error if the persistent mode is enabled since the sorting method doesn't persist its chunks.
+    pub(crate) fn check_not_persistent(
+        &self,
+        method: &str,
+    ) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>> {
+        return match self.persistence {
+            Some(_) => Err(SortError::InvalidConfig(format!(
+                "{} is not supported in the persistent mode",
+                method
+            ))),
+            None => Ok(()),
+        };
+    }
+
+    /// Returns the error of the fallible compare function once it has failed, the chunks dumped after the failure
+    /// are not ordered so they are not merged.
+    fn check_compare_failure(
+        &self,
+        ctx: &SortContext<T>,
+    ) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>> {
+        return match ctx.compare_failure.as_ref().and_then(|failure| failure.take()) {
+            Some(err) => Err(SortError::CompareError(err)),
+            None => Ok(()),
+        };
+    }
+
+    /// Builds a chunk of a sorted run sampling its items if the sorting picks key range splitters.
+    fn build_run(
+        &self,
+        ctx: &SortContext<T>,
+        items: impl IntoIterator<Item = T>,
+    ) -> Result<ExternalChunkFile, SortError<C::SerializationError, C::DeserializationError, E>> {
+        return match ctx.samples {
+            Some(samples) => self.build_chunk(ctx, samples.sample(items)),
+            None => self.build_chunk(ctx, items),
+        };
+    }
+
+    fn build_chunk(
+        &self,
+        ctx: &SortContext<T>,
+        items: impl IntoIterator<Item = T>,
+    ) -> Result<ExternalChunkFile, SortError<C::SerializationError, C::DeserializationError, E>> {
+        self.check_cancelled(ctx)?;
+        #[cfg(feature = "tracing")]
+        let span = chunk_span().entered();
+        let started = Instant::now();
+        let external_chunk = match ctx.persistence {
+            Some(persistence) => C::build_named_file(&persistence.next_chunk_path(), items, self.rw_buf_size),
+            None => C::build_file(&self.tmp_dir, items, self.rw_buf_size),
+        }
+        .map_err(Self::map_chunk_error)?;
+        ctx.progress.on_chunk_written(external_chunk.len(), started.elapsed());
+        #[cfg(feature = "tracing")]
+        record_chunk(&span, &external_chunk);
+
+        return Ok(external_chunk);
+    }
+
+    fn map_chunk_error(
+        err: ExternalChunkError<C::SerializationError>,
+    ) -> SortError<C::SerializationError, C::DeserializationError, E> {
+        match err {
+            ExternalChunkError::IO(err) => SortError::IO(err),
+            ExternalChunkError::SerializationError(err) => SortError::SerializationError(err),
+        }
+    }
+}
+
+impl<T, E, B> ExternalSorter<T, E, B, RmpExternalChunk<T>>
+where
+    T: Send + Serialize + DeserializeOwned,
+    E: Error,
+    B: ChunkBufferBuilder<T>,
+{
+    /// Sorts data from the input using a key extraction function computing the key only once per item.
+    /// Items are paired with their keys which are kept through buffering, dumping and merging
+    /// and stripped from the sorted items. The pairs are sorted by a sorter sharing the thread pool,
+    /// the temporary directory and the settings of this one, the combiner is applied to the items of the pairs.
+    /// Returns an iterator that can be used to get sorted data stream.
+    ///
+    /// # Arguments
+    /// * `input` - Input stream data to be fetched from
+    /// * `key_fn` - Function to be used to extract item key
+    #[allow(clippy::type_complexity)]
+    pub fn sort_by_cached_key<I, K, KF>(
+        &self,
+        input: I,
+        key_fn: KF,
+    ) -> Result<
+        SortedByCachedKey<K, T, E, impl Fn(&(K, T), &(K, T)) -> Ordering, RmpExternalChunk<(K, T)>>,
+        SortError<rmp_serde::encode::Error, rmp_serde::decode::Error, E>,
+    >
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        T: 'static,
+        K: Ord + Send + Serialize + DeserializeOwned + 'static,
+        KF: Fn(&T) -> K,
+        B: ChunkBufferBuilder<(K, T)> + Clone,
+    {
+        self.check_not_persistent("sort_by_cached_key")?;
+        let sorter = self.keyed_sorter::<K>();
+        let input = input.into_iter().map(|item| item.map(|item| (key_fn(&item), item)));
+        let items = sorter.sort_by(input, |a: &(K, T), b: &(K, T)| a.0.cmp(&b.0))?;
+
+        return Ok(SortedByCachedKey { items });
+    }
+
+    /// Returns a sorter of the items paired with their keys sharing the thread pool, the temporary directory
+    /// and the settings of this sorter.
+    #[allow(clippy::type_complexity)]
+    fn keyed_sorter<K>(&self) -> ExternalSorter<(K, T), E, B, RmpExternalChunk<(K, T)>>
+    where
+        T: 'static,
+        K: Send + Serialize + DeserializeOwned + 'static,
+        B: ChunkBufferBuilder<(K, T)> + Clone,
+    {
+        let combiner = self.combiner.as_ref().map(|combiner| {
+            let combine = Arc::clone(&combiner.combine);
+            return Combiner {
+                combine: Arc::new(move |(key, acc): (K, T), (_, item): (K, T)| (key, combine(acc, item))),
+                boxed: |combine| {
+                    let combine = Arc::clone(combine);
+                    return Box::new(move |acc, item| combine(acc, item));
+                },
+            };
+        });
+        let prefetch = self.prefetch.map(|prefetch| Prefetch {
+            batch_size: prefetch.batch_size,
+            queue_size: prefetch.queue_size,
+            open: PrefetchedChunk::new::<RmpExternalChunk<(K, T)>>,
+        });
+
+        return ExternalSorter {
+            thread_pool: Arc::clone(&self.thread_pool),
+            tmp_dir: Arc::clone(&self.tmp_dir),
+            buffer_builder: self.buffer_builder.clone(),
+            rw_buf_size: self.rw_buf_size,
+            max_merge_fanin: self.max_merge_fanin,
+            pipeline: self.pipeline,
+            prefetch,
+            merger_kind: self.merger_kind,
+            run_generation: self.run_generation,
+            memory_budget: self.memory_budget,
+            combiner,
+            unstable: self.unstable,
+            cancellation: self.cancellation.clone(),
+            progress_observer: self.progress_observer.clone(),
+            persistence: None,
+            external_chunk_type: PhantomData,
+            item_type: PhantomData,
+            input_error_type: PhantomData,
+        };
+    }
+}
+
+/// Push-based sorter input. Items are added to the chunk buffer which is sorted and dumped every time
+/// it is full the same way [`ExternalSorter::sort_by`] does it with the sorting buffer run generation strategy.
+/// Other run generation strategies and pipelining are not applied to the pushed items.
+pub struct SortSink<'a, T, E, B, C, F>
+where
+    T: Send,
+    E: Error,
+    B: ChunkBufferBuilder<T>,
+    C: ExternalChunk<T>,
+    F: Fn(&T, &T) -> Ordering,
+{
+    sorter: &'a ExternalSorter<T, E, B, C>,
+    buffer: B::Buffer,
+    chunks: Vec<(usize, ExternalChunkFile)>,
+    compare: F,
+    ctx: SortContext<'a, T>,
+    // the first error occurred while the items were added by `Extend::extend`
+    error: Option<SortError<C::SerializationError, C::DeserializationError, E>>,
+}
+
+impl<'a, T, E, B, C, F> SortSink<'a, T, E, B, C, F>
+where
+    T: Send,
+    E: Error,
+    B: ChunkBufferBuilder<T>,
+    C: ExternalChunk<T>,
+    F: Fn(&T, &T) -> Ordering + Sync + Send,
+{
+    /// Adds an item to the sink. If the buffer gets full it is sorted and dumped.
+    /// The sink should not be used anymore once an error is returned.
+    ///
+    /// # Arguments
+    /// * `item` - Item to be sorted
+    pub fn push(&mut self, item: T) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        self.ctx.progress.on_item_read();
+        return catch_panic(|| self.push_item(item));
+    }
+
+    /// Finishes the sorting. Returns an iterator that can be used to get sorted data stream.
+    /// An error occurred while the items were added by [`Extend::extend`] is returned here.
+    #[allow(clippy::type_complexity)]
+    pub fn finish(
+        mut self,
+    ) -> Result<SortedIterator<T, E, F, C>, SortError<C::SerializationError, C::DeserializationError, E>> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        return catch_panic(|| {
+            let memory_chunk =
+                (!self.buffer.is_empty()).then(|| self.sorter.sort_in_memory(&self.ctx, self.buffer, &self.compare));
+
+            self.sorter
+                .merge_chunks(&self.ctx, self.chunks, memory_chunk, self.compare)
+        });
+    }
+
+    fn push_item(&mut self, item: T) -> Result<(), SortError<C::SerializationError, C::DeserializationError, E>> {
+        self.sorter.check_cancelled(&self.ctx)?;
+        self.buffer.push(item);
+
+        if self.buffer.is_full() {
+            let buffer = mem::replace(&mut self.buffer, self.sorter.buffer_builder.build());
+            let chunk = self.sorter.create_chunk(&self.ctx, buffer, &self.compare)?;
+            self.sorter
+                .push_chunk(&self.ctx, &mut self.chunks, chunk, &self.compare)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Returns the dumped chunks and the sorted in-memory chunk.
+    fn into_chunks(self) -> (Vec<(usize, ExternalChunkFile)>, Option<Vec<T>>) {
+        let memory_chunk =
+            (!self.buffer.is_empty()).then(|| self.sorter.sort_in_memory(&self.ctx, self.buffer, &self.compare));
+
+        return (self.chunks, memory_chunk);
+    }
+}
+
+impl<'a, T, E, B, C, F> Extend<T> for SortSink<'a, T, E, B, C, F>
+where
+    T: Send,
+    E: Error,
+    B: ChunkBufferBuilder<T>,
+    C: ExternalChunk<T>,
+    F: Fn(&T, &T) -> Ordering + Sync + Send,
+{
+    /// Adds the items to the sink. Since errors can't be returned from here the first error is kept
+    /// and returned by the next [`SortSink::push`] or [`SortSink::finish`] call, the rest of the items is dropped.
+    fn extend<I: IntoIterator<Item = T>>(&mut self, items: I) {
+        for item in items {
+            if self.error.is_some() {
+                return;
+            }
+            self.ctx.progress.on_item_read();
+            if let Err(err) = catch_panic(|| self.push_item(item)) {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// Evenly spaced sample of a bounded number of items. Every `stride`-th pushed item is kept,
+/// once the sample is full every other kept item is dropped and the stride is doubled.
+struct Sample<T> {
+    items: Vec<T>,
+    capacity: usize,
+    stride: usize,
+    pushed: usize,
+    clone: fn(&T) -> T,
+}
+
+impl<T> Sample<T> {
+    fn new(capacity: usize, clone: fn(&T) -> T) -> Self {
+        Sample {
+            items: Vec::new(),
+            capacity: capacity.max(1),
+            stride: 1,
+            pushed: 0,
+            clone,
+        }
+    }
+
+    fn push(&mut self, item: &T) {
+        if self.pushed % self.stride == 0 {
+            if self.items.len() >= self.capacity {
+                let mut idx = 0;
+                self.items.retain(|_| {
+                    idx += 1;
+                    return idx % 2 == 1;
+                });
+                self.stride *= 2;
+            }
+            if self.pushed % self.stride == 0 {
+                self.items.push((self.clone)(item));
+            }
+        }
+        self.pushed += 1;
+    }
+}
+
+/// Samples of the sorted chunks created by a sorting the key range splitters are picked from.
+/// Every chunk is sampled separately while it is written, each sampled item is paired with the number
+/// of the chunk items it stands for.
+pub(crate) struct ChunkSamples<T> {
+    capacity: usize,
+    clone: fn(&T) -> T,
+    samples: Mutex<Vec<(T, usize)>>,
+}
+
+impl<T> ChunkSamples<T> {
+    fn new(capacity: usize) -> Self
+    where
+        T: Clone,
+    {
+        ChunkSamples {
+            capacity: capacity.max(1),
+            clone: T::clone,
+            samples: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the chunk items sampling them while they are written.
+    fn sample<I: IntoIterator<Item = T>>(&self, items: I) -> SampledItems<'_, I::IntoIter, T> {
+        return SampledItems {
+            items: items.into_iter(),
+            sample: Some(Sample::new(self.capacity, self.clone)),
+            samples: self,
+        };
+    }
+
+    /// Samples the chunk kept in memory.
+    fn add_items(&self, items: &[T]) {
+        let mut sample = Sample::new(self.capacity, self.clone);
+        for item in items {
+            sample.push(item);
+        }
+        self.add(sample);
+    }
+
+    /// Adds the sample of a chunk. Once the samples grow twice over the capacity every other sampled item
+    /// is dropped and the weight of the kept ones is doubled.
+    fn add(&self, sample: Sample<T>) {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        samples.extend(sample.items.into_iter().map(|item| (item, sample.stride)));
+        if samples.len() > 2 * self.capacity {
+            let mut idx = 0;
+            samples.retain_mut(|(_, weight)| {
+                idx += 1;
+                *weight *= 2;
+                return idx % 2 == 1;
+            });
+        }
+    }
+
+    /// Picks up to `partitions - 1` splitters dividing the sampled items into key ranges of about the same
+    /// number of items.
+    fn splitters(&self, partitions: usize, compare: impl Fn(&T, &T) -> Ordering) -> Vec<T> {
+        let mut samples = mem::take(&mut *self.samples.lock().unwrap_or_else(PoisonError::into_inner));
+        samples.sort_by(|(a, _), (b, _)| compare(a, b));
+        let total: usize = samples.iter().map(|(_, weight)| weight).sum();
+
+        let mut splitters = Vec::with_capacity(partitions.saturating_sub(1));
+        let mut preceding = 0;
+        for (item, weight) in samples {
+            if splitters.len() + 1 < partitions && preceding >= (splitters.len() + 1) * total / partitions {
+                splitters.push(item);
+            }
+            preceding += weight;
+        }
+
+        return splitters;
+    }
+}
+
+/// Chunk items being sampled, the sample is added to the chunk samples once the items are dropped.
+struct SampledItems<'a, I, T> {
+    items: I,
+    sample: Option<Sample<T>>,
+    samples: &'a ChunkSamples<T>,
+}
+
+impl<I: Iterator<Item = T>, T> Iterator for SampledItems<'_, I, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.items.next()?;
+        if let Some(sample) = self.sample.as_mut() {
+            sample.push(&item);
+        }
+
+        return Some(item);
+    }
+}
+
+impl<I, T> Drop for SampledItems<'_, I, T> {
+    fn drop(&mut self) {
+        if let Some(sample) = self.sample.take() {
+            self.samples.add(sample);
+        }
+    }
+}
+
+/// Creates a span of a chunk file being written, the file size is recorded by [`record_chunk`].
+#[cfg(feature = "tracing")]
+fn chunk_span() -> tracing::Span {
+    tracing::debug_span!(
+        "build_chunk",
+        items = tracing::field::Empty,
+        bytes = tracing::field::Empty
+    )
+}
+
+/// Records the size of the written chunk file to its span.
+#[cfg(feature = "tracing")]
+fn record_chunk(span: &tracing::Span, chunk_file: &ExternalChunkFile) {
+    span.record("items", chunk_file.items());
+    span.record("bytes", chunk_file.len());
+}
+
+/// Spawns a chunk job on the thread pool scope. The created chunk is sent to the returned receiver.
+fn spawn_chunk_job<'scope, S: Error + Send + 'static>(
+    scope: &rayon::Scope<'scope>,
+    job: ChunkJob<'scope, S>,
+) -> ChunkReceiver<S> {
+    let (sender, receiver) = mpsc::sync_channel(1);
+
+    scope.spawn(move |_| {
+        // the receiver is gone only if chunk creation has already been aborted
+        let _ = sender.send(job());
+    });
+
+    return receiver;
+}
+
+/// Runs the sorting phase converting a panic raised by it into [`SortError::Panicked`].
+/// Temporary chunk files are removed while the panic unwinds.
+pub(crate) fn catch_panic<R, S, D, I>(
+    f: impl FnOnce() -> Result<R, SortError<S, D, I>>,
+) -> Result<R, SortError<S, D, I>>
+where
+    S: Error,
+    D: Error,
+    I: Error,
+{
+    // nothing created by the phase is used after a panic so it is safe to be unwound
+    return panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(SortError::Panicked(panic_message(payload))));
+}
+
+/// Extracts the message of a caught panic.
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let msg = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(msg), _) => msg.to_string(),
+        (_, Some(msg)) => msg.clone(),
+        (None, None) => "unknown panic".to_string(),
+    };
+    log::error!("sorting panicked: {}", msg);
+
+    return msg;
+}
+
+/// Sorts the buffer using either stable or unstable parallel sorting.
+fn sort_buffer<T, F>(buffer: &mut [T], compare: F, unstable: bool)
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    match unstable {
+        true => buffer.par_sort_unstable_by(&compare),
+        false => buffer.par_sort_by(&compare),
+    }
+}
+
+/// Combines consecutive equal items of the sorted items if the combiner is set.
+fn combine_items<'a, T, F>(
+    items: impl IntoIterator<Item = T> + 'a,
+    compare: F,
+    combiner: Option<&'a CombineFn<T>>,
+) -> impl Iterator<Item = T> + 'a
+where
+    F: Fn(&T, &T) -> Ordering + 'a,
+{
+    let mut items = items.into_iter().peekable();
+
+    return iter::from_fn(move || {
+        let mut item = items.next()?;
+        if let Some(combine) = combiner {
+            while let Some(next) = items.next_if(|next| compare(&item, next) == Ordering::Equal) {
+                item = combine(item, next);
+            }
+        }
+        return Some(item);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::path::Path;
+    use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
+    use std::sync::Arc;
+
+    use rand::seq::SliceRandom;
+    use rstest::*;
+
+    use super::{
+        CancellationToken, ExternalSorter, ExternalSorterBuilder, LimitedBufferBuilder, MergerKind, ProgressObserver,
+        RunGeneration, SortError, SortStats, MIN_READ_BUF_SIZE,
+    };
+
+    #[fixture]
+    fn tmp_dir() -> tempfile::TempDir {
+        tempfile::tempdir().unwrap()
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_external_sorter(#[case] reversed: bool) {
+        let input_sorted = 0..100;
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+
+        #[allow(clippy::redundant_closure)]
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(|item| Ok(item)));
+
+        let sorter: ExternalSorter<i32, _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(8, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .build()
+            .unwrap();
+
+        let compare = if reversed {
+            |a: &i32, b: &i32| a.cmp(b).reverse()
+        } else {
+            |a: &i32, b: &i32| a.cmp(b)
+        };
+
+        let result = sorter.sort_by(input, compare).unwrap();
+
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = if reversed {
+            Vec::from_iter(input_sorted.clone().rev())
+        } else {
+            Vec::from_iter(input_sorted.clone())
+        };
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_external_sorter_stability(
+        #[case] reversed: bool,
+        #[values(MergerKind::BinaryHeap, MergerKind::LoserTree)] merger_kind: MergerKind,
+    ) {
+        let input_sorted = (0..20).flat_map(|x|(0..5).map(move |y| (x, y)));
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // sort input by the second field to check sorting stability
+        input_shuffled.sort_by(|a: &(i32, i32), b: &(i32, i32)| if reversed {a.1.cmp(&b.1).reverse()} else {a.1.cmp(&b.1)});
+
+        #[allow(clippy::redundant_closure)]
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(|item| Ok(item)));
+
+        let sorter: ExternalSorter<(i32, i32), _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(8, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_merger(merger_kind)
+            .build()
+            .unwrap();
+
+        let compare = if reversed {
+            |a: &(i32, i32), b: &(i32, i32)| a.0.cmp(&b.0).reverse()
+        } else {
+            |a: &(i32, i32), b: &(i32, i32)| a.0.cmp(&b.0)
+        };
+
+        let result = sorter.sort_by(input, compare).unwrap();
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = if reversed {
+            Vec::from_iter(input_sorted.clone().rev())
+        } else {
+            Vec::from_iter(input_sorted.clone())
+        };
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(2)]
+    #[case(3)]
+    #[case(16)]
+    fn test_external_sorter_max_merge_fanin(#[case] fanin: usize) {
+        let input_sorted = (0..50).flat_map(|x| (0..4).map(move |y| (x, y)));
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // sort input by the second field to check sorting stability
+        input_shuffled.sort_by_key(|a: &(i32, i32)| a.1);
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<(i32, i32), _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_max_merge_fanin(fanin)
+            .build()
+            .unwrap();
+
+        let result = sorter.sort_by(input, |a, b| a.0.cmp(&b.0)).unwrap();
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter(input_sorted);
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(RunGeneration::SortBuffer, None, 7)]
+    #[case(RunGeneration::SortBuffer, Some(2), 7)]
+    #[case(RunGeneration::SortBuffer, None, 1000)]
+    #[case(RunGeneration::ReplacementSelection, None, 7)]
+    #[case(RunGeneration::NaturalRuns, None, 7)]
+    fn test_external_sorter_unique(
+        #[case] run_generation: RunGeneration,
+        #[case] pipeline_depth: Option<usize>,
+        #[case] buffer_size: usize,
+    ) {
+        let input_sorted = (0..50).flat_map(|x| (0..4).map(move |y| (x, y)));
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // sort input by the second field so that the first of equal items is the one to be kept
+        input_shuffled.sort_by_key(|a: &(i32, i32)| a.1);
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(buffer_size, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_max_merge_fanin(3)
+            .with_run_generation(run_generation)
+            .with_unique(true);
+        if let Some(depth) = pipeline_depth {
+            builder = builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<(i32, i32), _> = builder.build().unwrap();
+
+        let result = sorter.sort_by(input, |a, b| a.0.cmp(&b.0)).unwrap();
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter((0..50).map(|x| (x, 0)));
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(RunGeneration::SortBuffer, None, None)]
+    #[case(RunGeneration::SortBuffer, Some(2), None)]
+    #[case(RunGeneration::ReplacementSelection, None, None)]
+    #[case(RunGeneration::NaturalRuns, None, None)]
+    #[case(RunGeneration::SortBuffer, None, Some(10))]
+    fn test_external_sorter_combiner(
+        #[case] run_generation: RunGeneration,
+        #[case] pipeline_depth: Option<usize>,
+        #[case] limit: Option<usize>,
+    ) {
+        // every key occurs (key % 5 + 1) times with counts 1, 2, ...
+        let mut input_shuffled = Vec::from_iter((0..50).flat_map(|key| (1..=key % 5 + 1).map(move |cnt| (key, cnt))));
+        input_shuffled.shuffle(&mut rand::thread_rng());
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_max_merge_fanin(3)
+            .with_run_generation(run_generation)
+            .with_combiner(|acc: (i32, i32), item: (i32, i32)| (acc.0, acc.1 + item.1));
+        if let Some(depth) = pipeline_depth {
+            builder = builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<(i32, i32), _> = builder.build().unwrap();
+
+        let compare = |a: &(i32, i32), b: &(i32, i32)| a.0.cmp(&b.0);
+        let result = match limit {
+            Some(limit) => sorter.sort_by_limit(input, compare, limit).unwrap(),
+            None => sorter.sort_by(input, compare).unwrap(),
+        };
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter(
+            (0..50)
+                .map(|key| (key, (1..=key % 5 + 1).sum()))
+                .take(limit.unwrap_or(usize::MAX)),
+        );
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(3)]
+    fn test_external_sorter_partitioned_combiner(#[case] partitions: usize) {
+        // every key occurs (key % 5 + 1) times with counts 1, 2, ...
+        let mut input_shuffled = Vec::from_iter((0..50).flat_map(|key| (1..=key % 5 + 1).map(move |cnt| (key, cnt))));
+        input_shuffled.shuffle(&mut rand::thread_rng());
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<(i32, i32), _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_combiner(|acc: (i32, i32), item: (i32, i32)| (acc.0, acc.1 + item.1))
+            .build()
+            .unwrap();
+
+        let ranges = sorter
+            .sort_by_partitioned(input, |a, b| a.0.cmp(&b.0), partitions)
+            .unwrap();
+
+        // equal items spread over many chunks are combined across them
+        let actual_result: Result<Vec<(i32, i32)>, _> = ranges.into_iter().flatten().collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter((0..50).map(|key| (key, (1..=key % 5 + 1).sum())));
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[test]
+    fn test_external_sorter_group_by_key() {
+        let mut input_shuffled = Vec::from_iter((0..50).flat_map(|key| (0..key % 3 + 1).map(move |idx| (key, idx))));
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // sort input by the second field so that group items are in ascending order
+        input_shuffled.sort_by_key(|a: &(i32, i32)| a.1);
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<(i32, i32), _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .build()
+            .unwrap();
+
+        let mut grouped = sorter
+            .sort_by(input, |a, b| a.0.cmp(&b.0))
+            .unwrap()
+            .group_by_key(|item| item.0);
+
+        let mut actual_result = Vec::new();
+        while let Some(group) = grouped.next_group() {
+            let (key, group) = group.unwrap();
+            let indices: Result<Vec<i32>, _> = group.map(|item| item.map(|item| item.1)).collect();
+            actual_result.push((key, indices.unwrap()));
+        }
+        let expected_result = Vec::from_iter((0..50).map(|key| (key, Vec::from_iter(0..key % 3 + 1))));
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    fn test_external_sorter_by_key(#[values(false, true)] cached: bool) {
+        let input_sorted = Vec::from_iter((0..100).map(|x| format!("Item{:03}", x)));
+
+        let mut input_shuffled = input_sorted.clone();
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // keys are case insensitive so mixed case items are sorted by the lower case order
+        let input_shuffled = input_shuffled.into_iter().map(|item| match rand::random() {
+            true => item.to_uppercase(),
+            false => item,
+        });
+        let input = Vec::from_iter(input_shuffled.map(Ok::<_, io::Error>));
+
+        let sorter: ExternalSorter<String, _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(8, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .build()
+            .unwrap();
+
+        let actual_result: Result<Vec<String>, _> = if cached {
+            let key_calls = AtomicU64::new(0);
+            let sorted = sorter
+                .sort_by_cached_key(input, |item| {
+                    key_calls.fetch_add(1, atomic::Ordering::Relaxed);
+                    item.to_lowercase()
+                })
+                .unwrap();
+            assert!(!sorted.is_in_memory());
+
+            // the keys are stripped from the sorted items
+            let actual_result: Result<Vec<String>, _> = sorted.collect();
+            // the key is computed once per item, not on every comparison
+            assert_eq!(key_calls.load(atomic::Ordering::Relaxed), 100);
+            actual_result
+        } else {
+            sorter.sort_by_key(input, |item| item.to_lowercase()).unwrap().collect()
+        };
+        let actual_result = Vec::from_iter(actual_result.unwrap().into_iter().map(|item| item.to_lowercase()));
+        let expected_result = Vec::from_iter(input_sorted.into_iter().map(|item| item.to_lowercase()));
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    fn test_external_sorter_cached_key_combiner(tmp_dir: tempfile::TempDir) {
+        let sorter: ExternalSorter<(String, u32), io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(8, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .with_combiner(|(word, acc), (_, count)| (word, acc + count))
+            .build()
+            .unwrap();
+
+        let input = Vec::from_iter((0..100).rev().map(|x| Ok((format!("Word{}", x % 10), 1))));
+        let sorted = sorter
+            .sort_by_cached_key(input, |(word, _)| word.to_lowercase())
+            .unwrap();
+
+        // the combiner is applied to the items paired with equal keys
+        let actual_result: Vec<(String, u32)> = sorted.map(Result::unwrap).collect();
+        let expected_result = Vec::from_iter((0..10).map(|x| (format!("Word{}", x), 10)));
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    fn test_external_sorter_unstable(#[values(MergerKind::BinaryHeap, MergerKind::LoserTree)] merger_kind: MergerKind) {
+        let input_sorted = (0..50).flat_map(|x| (0..4).map(move |y| (x, y)));
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<(i32, i32), _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_merger(merger_kind)
+            .with_unstable_sort(true)
+            .build()
+            .unwrap();
+
+        let result = sorter.sort_by(input, |a, b| a.0.cmp(&b.0)).unwrap();
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let mut actual_result = actual_result.unwrap();
+        assert!(actual_result.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        // equal items may come in any order
+        actual_result.sort();
+        assert_eq!(actual_result, Vec::from_iter(input_sorted))
+    }
+
+    #[rstest]
+    fn test_external_sorter_non_copy_compare(
+        #[values(MergerKind::BinaryHeap, MergerKind::LoserTree)] merger_kind: MergerKind,
+        #[values(RunGeneration::SortBuffer, RunGeneration::ReplacementSelection)] run_generation: RunGeneration,
+    ) {
+        // the compare function owns a runtime-built ordering of the keys
+        let key_order = Vec::from_iter((0..50).rev());
+        let compare = move |a: &i32, b: &i32| key_order[*a as usize].cmp(&key_order[*b as usize]);
+
+        let mut input_shuffled = Vec::from_iter(0..50);
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<i32, _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_max_merge_fanin(3)
+            .with_merger(merger_kind)
+            .with_run_generation(run_generation)
+            .build()
+            .unwrap();
+
+        let result = sorter.sort_by(input, compare).unwrap();
+
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), Vec::from_iter((0..50).rev()))
+    }
+
+    #[rstest]
+    #[case(7, Some(14))]
+    #[case(200, None)]
+    fn test_external_sorter_sink(
+        tmp_dir: tempfile::TempDir,
+        #[case] buffer_size: usize,
+        #[case] chunks_dumped: Option<u64>,
+    ) {
+        let mut input_shuffled = Vec::from_iter(0..100);
+        input_shuffled.shuffle(&mut rand::thread_rng());
+
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(buffer_size, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .with_max_merge_fanin(3)
+            .build()
+            .unwrap();
+
+        let mut sink = sorter.sink_by(|a: &i32, b: &i32| a.cmp(b).reverse());
+        let (pushed, extended) = input_shuffled.split_at(input_shuffled.len() / 2);
+        for &item in pushed {
+            sink.push(item).unwrap();
+        }
+        sink.extend(extended.iter().copied());
+
+        let result = sink.finish().unwrap();
+        // every filled buffer is dumped as it is by the pulling sorter, the last one is merged from memory
+        match chunks_dumped {
+            Some(chunks_dumped) => {
+                let stats = result.stats();
+                assert_eq!(stats.items_read, 100);
+                assert_eq!(stats.chunks_written - stats.intermediate_merges, chunks_dumped);
+                assert!(stats.intermediate_merges > 0);
+            }
+            None => assert!(result.is_in_memory()),
+        }
+
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), Vec::from_iter((0..100).rev()))
+    }
+
+    #[rstest]
+    fn test_external_sorter_sink_extend_error(tmp_dir: tempfile::TempDir) {
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .build()
+            .unwrap();
+
+        let mut sink = sorter.sink_by(|a: &i32, b: &i32| match (a, b) {
+            (&13, _) | (_, &13) => panic!("unexpected item"),
+            (a, b) => a.cmp(b),
+        });
+        // the second buffer fails to be sorted, the error is kept until the sink is finished
+        sink.extend(0..20);
+
+        match sink.finish() {
+            Err(SortError::Panicked(msg)) => assert_eq!(msg, "unexpected item"),
+            _ => panic!("extend error is expected to be returned"),
+        }
+    }
+
+    #[rstest]
+    #[case(Vec::from_iter(0..50), 100, None)]
+    #[case(Vec::from_iter((0..25).flat_map(|x| [x, x + 25])), 20, Some(true))]
+    #[case(Vec::from_iter(0..50), 20, Some(false))]
+    fn test_external_sorter_try_sort_by(
+        #[case] input: Vec<i32>,
+        #[case] max_distance: i32,
+        #[case] expected_failure: Option<bool>,
+    ) {
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<i32, _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .build()
+            .unwrap();
+
+        // sorted input chunks compare only adjacent items, distant ones are compared during the merge
+        let result = sorter.try_sort_by(input, |a: &i32, b: &i32| match (a - b).abs() <= max_distance {
+            true => Ok(a.cmp(b)),
+            false => Err(io::Error::other("items too distant")),
+        });
+
+        match expected_failure {
+            None => {
+                let actual_result: Result<Vec<i32>, _> = result.unwrap().collect();
+                assert_eq!(actual_result.unwrap(), Vec::from_iter(0..50));
+            }
+            Some(before_merge) => {
+                let err = match result {
+                    Err(err) => {
+                        assert!(before_merge);
+                        err
+                    }
+                    Ok(sorted) => {
+                        assert!(!before_merge);
+                        sorted.collect::<Result<Vec<i32>, _>>().unwrap_err()
+                    }
+                };
+                assert!(matches!(err, SortError::CompareError(_)));
+                assert_eq!(err.to_string(), "items comparison failed: items too distant");
+            }
+        }
+    }
+
+    #[rstest]
+    #[case(None, None)]
+    #[case(Some(2), None)]
+    #[case(None, Some(2))]
+    fn test_external_sorter_panicked(#[case] pipeline_depth: Option<usize>, #[case] fanin: Option<usize>) {
+        let mut input_shuffled = Vec::from_iter(0..50);
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // the panicking item is put in the first chunk so that it is sorted before the merge
+        input_shuffled.retain(|item| *item != 42);
+        input_shuffled.insert(0, 42);
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"));
+        if let Some(depth) = pipeline_depth {
+            builder = builder.with_pipeline_depth(depth);
+        }
+        if let Some(fanin) = fanin {
+            builder = builder.with_max_merge_fanin(fanin);
+        }
+        let sorter: ExternalSorter<i32, _> = builder.build().unwrap();
+
+        let result = sorter.sort_by(input, |a: &i32, b: &i32| match (a, b) {
+            (&42, _) | (_, &42) => panic!("unexpected item"),
+            (a, b) => a.cmp(b),
+        });
+
+        match result {
+            Err(SortError::Panicked(msg)) => assert_eq!(msg, "unexpected item"),
+            _ => panic!("panic is expected to be caught"),
+        }
+
+        // the sorter is still usable after the panic
+        let result = sorter.sort(Vec::from_iter((0..50).rev().map(Ok))).unwrap();
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), Vec::from_iter(0..50));
+    }
+
+    #[rstest]
+    fn test_external_sorter_panicked_merge(#[values(false, true)] partitioned: bool) {
+        let mut input_shuffled = Vec::from_iter(0..50);
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<i32, _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .build()
+            .unwrap();
+
+        // the compare function starts panicking once the chunks are created
+        let merging = AtomicBool::new(false);
+        let compare = |a: &i32, b: &i32| match merging.load(atomic::Ordering::Relaxed) {
+            true => panic!("merge failed"),
+            false => a.cmp(b),
+        };
+        let mut result = match partitioned {
+            true => sorter.sort_by_partitioned(input, compare, 1).unwrap().pop().unwrap(),
+            false => sorter.sort_by(input, compare).unwrap(),
+        };
+        merging.store(true, atomic::Ordering::Relaxed);
+
+        let actual_result = Vec::from_iter(result.by_ref());
+        match actual_result.last() {
+            Some(Err(SortError::Panicked(msg))) => assert_eq!(msg, "merge failed"),
+            _ => panic!("panic is expected to be caught"),
+        }
+        assert!(result.next().is_none());
+    }
+
+    /// Subscriber collecting the names and the fields of the created spans.
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    #[allow(clippy::type_complexity)]
+    struct SpanCollector {
+        spans: std::sync::Mutex<Vec<(&'static str, Vec<(&'static str, String)>)>>,
+        // names of the entered spans in the order they were entered
+        entered: std::sync::Mutex<Vec<&'static str>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl SpanCollector {
+        /// Returns the fields of the spans with the given name.
+        fn fields(&self, name: &str) -> Vec<Vec<(&'static str, String)>> {
+            let spans = self.spans.lock().unwrap();
+            return Vec::from_iter(spans.iter().filter(|span| span.0 == name).map(|span| span.1.clone()));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    struct FieldCollector<'a>(&'a mut Vec<(&'static str, String)>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldCollector<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.push((field.name(), value.to_string()));
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push((field.name(), format!("{:?}", value)));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanCollector {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            let mut fields = Vec::new();
+            attrs.record(&mut FieldCollector(&mut fields));
+            spans.push((attrs.metadata().name(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut FieldCollector(&mut spans[span.into_u64() as usize - 1].1));
+        }
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {}
+
+        fn enter(&self, span: &tracing::span::Id) {
+            let name = self.spans.lock().unwrap()[span.into_u64() as usize - 1].0;
+            self.entered.lock().unwrap().push(name);
+        }
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[rstest]
+    fn test_external_sorter_tracing(tmp_dir: tempfile::TempDir) {
+        let collector = Arc::new(SpanCollector::default());
+
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(10, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .build()
+            .unwrap();
+
+        let sorted = tracing::subscriber::with_default(Arc::clone(&collector), || {
+            let result = sorter.sort(Vec::from_iter((0..25).rev().map(Ok))).unwrap();
+            Result::<Vec<i32>, _>::from_iter(result).unwrap()
+        });
+        assert_eq!(sorted, Vec::from_iter(0..25));
+
+        let field = |name: &'static str, value: &str| (name, value.to_string());
+        assert_eq!(collector.fields("sort"), vec![vec![field("threads", "2")]]);
+        // 2 chunks are dumped, the last 5 items are kept in memory
+        assert_eq!(
+            collector.fields("create_chunk"),
+            vec![
+                vec![field("chunk", "0"), field("items", "10")],
+                vec![field("chunk", "1"), field("items", "10")]
+            ]
+        );
+        let chunks = collector.fields("build_chunk");
+        assert_eq!(chunks.len(), 2);
+        for chunk in chunks {
+            assert_eq!(chunk[0], field("items", "10"));
+            assert_eq!(chunk[1].0, "bytes");
+            assert!(chunk[1].1.parse::<u64>().unwrap() > 0);
+        }
+        assert_eq!(
+            collector.fields("merge"),
+            vec![vec![field("merger", "binary_heap"), field("chunks", "3")]]
+        );
+        // the merge span is entered for every merged item and the final call finding the chunks exhausted
+        let entered = collector.entered.lock().unwrap();
+        assert_eq!(entered.iter().filter(|name| **name == "merge").count(), 26);
+    }
+
+    #[derive(Default)]
+    struct CountingObserver {
+        chunks_written: AtomicU64,
+        intermediate_merges: AtomicU64,
+        merges_finished: AtomicU64,
+    }
+
+    impl ProgressObserver for CountingObserver {
+        fn on_chunk_written(&self, _stats: &SortStats) {
+            self.chunks_written.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        fn on_intermediate_merge(&self, _stats: &SortStats) {
+            self.intermediate_merges.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        fn on_merge_finished(&self, _stats: &SortStats) {
+            self.merges_finished.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[rstest]
+    #[case(None)]
+    #[case(Some(2))]
+    fn test_external_sorter_progress(tmp_dir: tempfile::TempDir, #[case] pipeline_depth: Option<usize>) {
+        let observer = Arc::new(CountingObserver::default());
+
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(10, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .with_max_merge_fanin(3)
+            .with_progress_observer(Arc::clone(&observer));
+        if let Some(depth) = pipeline_depth {
+            builder = builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<i32, io::Error> = builder.build().unwrap();
+
+        let mut result = sorter.sort(Vec::from_iter((0..95).rev().map(Ok))).unwrap();
+        // every sorting collects its own statistics
+        let other = sorter.sort(Vec::from_iter((0..5).map(Ok))).unwrap();
+        assert_eq!(result.by_ref().count(), 95);
+
+        // 9 chunks are dumped and the last 5 items are kept in memory,
+        // every 3 chunks of the same level are merged into one, which takes 4 merges
+        let stats = result.stats();
+        assert_eq!(stats.items_read, 95);
+        assert_eq!(stats.chunks_written, 13);
+        assert_eq!(stats.intermediate_merges, 4);
+        assert_eq!(stats.items_merged, 95);
+        assert!(stats.bytes_spilled > 0);
+
+        assert_eq!(observer.chunks_written.load(atomic::Ordering::Relaxed), 13);
+        assert_eq!(observer.intermediate_merges.load(atomic::Ordering::Relaxed), 4);
+        assert_eq!(observer.merges_finished.load(atomic::Ordering::Relaxed), 1);
+
+        assert_eq!(other.stats().items_read, 5);
+        assert_eq!(other.stats().chunks_written, 0);
+    }
+
+    #[rstest]
+    fn test_external_sorter_progress_in_memory(tmp_dir: tempfile::TempDir) {
+        let observer = Arc::new(CountingObserver::default());
+
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(10, true))
+            .with_tmp_dir(tmp_dir.path())
+            .with_progress_observer(Arc::clone(&observer))
+            .build()
+            .unwrap();
+
+        let mut result = sorter.sort(Vec::from_iter((0..5).rev().map(Ok))).unwrap();
+        assert!(result.is_in_memory());
+        assert_eq!(result.by_ref().count(), 5);
+
+        let stats = result.stats();
+        assert_eq!(stats.items_read, 5);
+        assert_eq!(stats.items_merged, 5);
+        assert_eq!(stats.chunks_written, 0);
+        assert_eq!(observer.merges_finished.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[rstest]
+    fn test_external_sorter_try_sort_by_stops_merging(tmp_dir: tempfile::TempDir) {
+        let observer = Arc::new(CountingObserver::default());
+
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .with_max_merge_fanin(3)
+            .with_progress_observer(Arc::clone(&observer))
+            .build()
+            .unwrap();
+
+        // the third chunk fails to be sorted, it completes a level of chunks though
+        let input = Vec::from_iter((0..21).map(Ok));
+        let result = sorter.try_sort_by(input, |a: &i32, b: &i32| match *a != 20 && *b != 20 {
+            true => Ok(a.cmp(b)),
+            false => Err(io::Error::other("broken item")),
+        });
+
+        assert!(matches!(result, Err(SortError::CompareError(_))));
+        assert_eq!(observer.chunks_written.load(atomic::Ordering::Relaxed), 3);
+        assert_eq!(observer.intermediate_merges.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(observer.merges_finished.load(atomic::Ordering::Relaxed), 0);
+    }
+
+    #[rstest]
+    #[case(None, RunGeneration::SortBuffer)]
+    #[case(Some(2), RunGeneration::SortBuffer)]
+    #[case(None, RunGeneration::ReplacementSelection)]
+    #[case(None, RunGeneration::NaturalRuns)]
+    fn test_external_sorter_cancelled(
+        tmp_dir: tempfile::TempDir,
+        #[case] pipeline_depth: Option<usize>,
+        #[case] run_generation: RunGeneration,
+    ) {
+        let token = CancellationToken::new();
+
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .with_run_generation(run_generation)
+            .with_cancellation(token.clone());
+        if let Some(depth) = pipeline_depth {
+            builder = builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<i32, io::Error> = builder.build().unwrap();
+
+        // the sorting is cancelled in the middle of the input
+        let input = (0..100).rev().map(move |item| {
+            if item == 50 {
+                token.cancel();
+            }
+            Ok(item)
+        });
+
+        match sorter.sort(input) {
+            Err(SortError::Cancelled) => {}
+            _ => panic!("sorting is expected to be cancelled"),
+        }
+        // the temporary chunk files are removed from the sorter directory
+        let sorter_dir = fs::read_dir(tmp_dir.path()).unwrap().next().unwrap().unwrap();
+        assert_eq!(fs::read_dir(sorter_dir.path()).unwrap().count(), 0);
+    }
+
+    #[rstest]
+    #[case(None)]
+    #[case(Some(2))]
+    fn test_external_sorter_cancellable(tmp_dir: tempfile::TempDir, #[case] pipeline_depth: Option<usize>) {
+        let default_token = CancellationToken::new();
+
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .with_cancellation(default_token.clone());
+        if let Some(depth) = pipeline_depth {
+            builder = builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<i32, io::Error> = builder.build().unwrap();
+
+        // only the sorting the token is given to is cancelled
+        let token = CancellationToken::new();
+        let cancelled_token = token.clone();
+        let input = (0..100).rev().map(move |item| {
+            if item == 50 {
+                cancelled_token.cancel();
+            }
+            Ok(item)
+        });
+        let result = sorter.sort_by_cancellable(input, i32::cmp, &token);
+        assert!(matches!(result, Err(SortError::Cancelled)));
+
+        let result = sorter.sort(Vec::from_iter((0..100).rev().map(Ok))).unwrap();
+        assert_eq!(result.map(Result::unwrap).collect::<Vec<_>>(), Vec::from_iter(0..100));
+
+        // the sorter token is overridden by the sorting one
+        default_token.cancel();
+        let result = sorter.sort(Vec::from_iter((0..100).map(Ok)));
+        assert!(matches!(result, Err(SortError::Cancelled)));
+
+        let input = Vec::from_iter((0..100).rev().map(Ok));
+        let token = CancellationToken::new();
+        let result = sorter.sort_by_cancellable(input, i32::cmp, &token).unwrap();
+        assert_eq!(result.map(Result::unwrap).collect::<Vec<_>>(), Vec::from_iter(0..100));
+    }
+
+    #[rstest]
+    #[case::sort("sort")]
+    #[case::try_sort_by("try_sort_by")]
+    #[case::limit("limit")]
+    #[case::partitioned("partitioned")]
+    #[case::sink("sink")]
+    #[case::memory("memory")]
+    fn test_external_sorter_cancelled_merge(tmp_dir: tempfile::TempDir, #[case] entry_point: &str) {
+        let token = CancellationToken::new();
+
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .with_cancellation(token.clone())
+            .build()
+            .unwrap();
+
+        let input = Vec::from_iter((0..100).rev().map(Ok));
+        let result: Box<dyn Iterator<Item = Result<i32, SortError<_, _, io::Error>>>> = match entry_point {
+            "sort" => Box::new(sorter.sort(input).unwrap()),
+            "try_sort_by" => Box::new(sorter.try_sort_by(input, |a, b| Ok::<_, io::Error>(a.cmp(b))).unwrap()),
+            "limit" => Box::new(sorter.sort_by_limit(input, i32::cmp, 50).unwrap()),
+            "partitioned" => Box::new(sorter.sort_by_partitioned(input, i32::cmp, 2).unwrap().remove(0)),
+            "sink" => {
+                let mut sink = sorter.sink();
+                sink.extend(input.into_iter().map(Result::unwrap));
+                Box::new(sink.finish().unwrap())
+            }
+            "memory" => Box::new(sorter.sort(input.into_iter().skip(95)).unwrap()),
+            _ => unreachable!(),
+        };
+        let actual_result = Vec::from_iter(
+            result
+                .inspect(|_| token.cancel())
+                .map(|item| item.map_err(|err| err.to_string())),
+        );
+
+        // the merge stops right after the first item reporting the cancellation
+        let expected_result = vec![Ok(0), Err("sorting cancelled".to_string())];
+        assert_eq!(actual_result, expected_result);
+    }
+
+    #[rstest]
+    fn test_external_sorter_persistent(tmp_dir: tempfile::TempDir) {
+        let dir = tmp_dir.path().join("persistent");
+        let new_sorter = |comparator_id: &str, token: Option<CancellationToken>| -> ExternalSorter<i32, io::Error> {
+            let mut builder = ExternalSorterBuilder::new()
+                .with_buffer(LimitedBufferBuilder::new(10, true))
+                .with_threads_number(2)
+                .with_tmp_dir(tmp_dir.path())
+                .with_max_merge_fanin(3)
+                .with_persistence(&dir, comparator_id);
+            if let Some(token) = token {
+                builder = builder.with_cancellation(token);
+            }
+            builder.build().unwrap()
+        };
+        let input = Vec::from_iter((0..100).rev());
+
+        // the first sorting is interrupted in the middle of the input
+        let token = CancellationToken::new();
+        let sorter = new_sorter("asc", Some(token.clone()));
+        let interrupted = input.clone().into_iter().enumerate().map(move |(index, item)| {
+            if index == 55 {
+                token.cancel();
+            }
+            Ok(item)
+        });
+        assert!(matches!(sorter.sort(interrupted), Err(SortError::Cancelled)));
+
+        let manifest = sorter.manifest().unwrap().unwrap();
+        assert_eq!(manifest.comparator_id, "asc");
+        assert_eq!(manifest.input_offset, 50);
+        assert!(!manifest.input_complete);
+        let chunks = Vec::from_iter(manifest.chunks.iter().map(|chunk| (chunk.level, chunk.items)));
+        assert_eq!(chunks, vec![(1, 30), (0, 10), (0, 10)]);
+
+        // the restarted sorting continues from the last persisted chunk
+        let sorter = new_sorter("asc", None);
+        let result = sorter.sort(input.clone().into_iter().map(Ok)).unwrap();
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), Vec::from_iter(0..100));
+
+        let manifest = sorter.manifest().unwrap().unwrap();
+        assert_eq!(manifest.input_offset, 100);
+        assert!(manifest.input_complete);
+        assert_eq!(manifest.chunks.iter().map(|chunk| chunk.items).sum::<u64>(), 100);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), manifest.chunks.len() + 1);
+
+        // the input is not read anymore once it is dumped completely
+        let unread = (0..100).map(|_| Err(io::Error::other("input is not expected to be read")));
+        let result = sorter.sort(unread).unwrap();
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), Vec::from_iter(0..100));
+
+        // the chunks sorted by another compare function are not reused
+        let other_sorter = new_sorter("desc", None);
+        assert!(matches!(
+            other_sorter.sort(input.clone().into_iter().map(Ok)),
+            Err(SortError::IO(_))
+        ));
+
+        sorter.clear_persisted().unwrap();
+        assert!(sorter.manifest().unwrap().is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[rstest]
+    fn test_external_sorter_persistent_cancelled_on_buffer_boundary(tmp_dir: tempfile::TempDir) {
+        let dir = tmp_dir.path().join("persistent");
+        let new_sorter = |token: Option<CancellationToken>| -> ExternalSorter<i32, io::Error> {
+            let mut builder = ExternalSorterBuilder::new()
+                .with_buffer(LimitedBufferBuilder::new(10, true))
+                .with_tmp_dir(tmp_dir.path())
+                .with_persistence(&dir, "asc");
+            if let Some(token) = token {
+                builder = builder.with_cancellation(token);
+            }
+            builder.build().unwrap()
+        };
+        let input = Vec::from_iter((0..100).rev());
+
+        // the sorting is cancelled right after the second buffer is dumped
+        let token = CancellationToken::new();
+        let sorter = new_sorter(Some(token.clone()));
+        let interrupted = input.clone().into_iter().enumerate().map(move |(index, item)| {
+            if index == 20 {
+                token.cancel();
+            }
+            Ok(item)
         });
+        assert!(matches!(sorter.sort(interrupted), Err(SortError::Cancelled)));
 
-        log::debug!("saving chunk data");
-        let external_chunk =
-            ExternalChunk::build(&self.tmp_dir, buffer, self.rw_buf_size).map_err(|err| match err {
-                ExternalChunkError::IO(err) => SortError::IO(err),
-                ExternalChunkError::SerializationError(err) => SortError::SerializationError(err),
-            })?;
+        let manifest = sorter.manifest().unwrap().unwrap();
+        assert_eq!(manifest.input_offset, 20);
+        assert!(!manifest.input_complete);
 
-        return Ok(external_chunk);
+        // the restarted sorting reads the rest of the input
+        let sorter = new_sorter(None);
+        let result = sorter.sort(input.into_iter().map(Ok)).unwrap();
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), Vec::from_iter(0..100));
+        assert!(sorter.manifest().unwrap().unwrap().input_complete);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::io;
-    use std::path::Path;
+    #[rstest]
+    // the compare function fails while a chunk is sorted
+    #[case(100, 0, (54, 55), 50, 5)]
+    // the compare function fails while two chunks are merged
+    #[case(40, 2, (5, 10), 10, 1)]
+    fn test_external_sorter_persistent_compare_error(
+        tmp_dir: tempfile::TempDir,
+        #[case] items: i32,
+        #[case] fanin: usize,
+        #[case] failing_pair: (i32, i32),
+        #[case] input_offset: u64,
+        #[case] persisted_chunks: usize,
+    ) {
+        let dir = tmp_dir.path().join("persistent");
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(10, true))
+            .with_tmp_dir(tmp_dir.path())
+            .with_persistence(&dir, "asc");
+        if fanin > 0 {
+            builder = builder.with_max_merge_fanin(fanin);
+        }
+        let sorter: ExternalSorter<i32, io::Error> = builder.build().unwrap();
 
-    use rand::seq::SliceRandom;
-    use rstest::*;
+        let input = Vec::from_iter((0..items).map(Ok));
+        let result = sorter.try_sort_by(input, |a: &i32, b: &i32| {
+            match (*a, *b) != failing_pair && (*b, *a) != failing_pair {
+                true => Ok(a.cmp(b)),
+                false => Err(io::Error::other("unexpected items")),
+            }
+        });
+        assert!(matches!(result, Err(SortError::CompareError(_))));
+
+        // the chunks dumped after the failure are not ordered, only the ones persisted before it are kept
+        let manifest = sorter.manifest().unwrap().unwrap();
+        assert_eq!(manifest.input_offset, input_offset);
+        assert!(!manifest.input_complete);
+        assert_eq!(manifest.chunks.len(), persisted_chunks);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), persisted_chunks + 1);
+
+        // the sorting is resumed from them
+        let result = sorter.sort(Vec::from_iter((0..items).map(Ok))).unwrap();
+        assert_eq!(result.map(Result::unwrap).collect::<Vec<_>>(), Vec::from_iter(0..items));
+    }
+
+    #[rstest]
+    fn test_external_sorter_persistent_unsupported(tmp_dir: tempfile::TempDir) {
+        let dir = tmp_dir.path().join("persistent");
+        let builder = || {
+            ExternalSorterBuilder::<i32, io::Error>::new()
+                .with_buffer(LimitedBufferBuilder::new(10, true))
+                .with_tmp_dir(tmp_dir.path())
+                .with_persistence(&dir, "asc")
+        };
 
-    use super::{ExternalSorter, ExternalSorterBuilder, LimitedBufferBuilder};
+        assert!(matches!(
+            builder().with_pipeline_depth(2).build(),
+            Err(SortError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            builder().with_run_generation(RunGeneration::NaturalRuns).build(),
+            Err(SortError::InvalidConfig(_))
+        ));
+
+        let sorter = builder().build().unwrap();
+        let input = || Vec::from_iter((0..20).map(Ok));
+
+        let result = sorter.sort_by_limit(input(), i32::cmp, 5);
+        assert!(matches!(result, Err(SortError::InvalidConfig(_))));
+        let result = sorter.sort_by_partitioned(input(), i32::cmp, 2);
+        assert!(matches!(result, Err(SortError::InvalidConfig(_))));
+        let result = sorter.sort_by_cached_key(input(), |item| -item);
+        assert!(matches!(result, Err(SortError::InvalidConfig(_))));
+
+        let mut sink = sorter.sink();
+        let result = sink.push(0);
+        assert!(matches!(result, Err(SortError::InvalidConfig(_))));
+
+        // nothing is persisted by the rejected sortings
+        assert!(sorter.manifest().unwrap().is_none());
+    }
+
+    #[rstest]
+    #[case(0, true)]
+    #[case(3, true)]
+    #[case(5, false)]
+    #[case(20, false)]
+    #[case(300, false)]
+    fn test_external_sorter_limit(#[case] limit: usize, #[case] in_memory: bool) {
+        let input_sorted = (0..50).flat_map(|x| (0..4).map(move |y| (x, y)));
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // sort input by the second field to check sorting stability
+        input_shuffled.sort_by_key(|a: &(i32, i32)| a.1);
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<(i32, i32), _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_max_merge_fanin(2)
+            .build()
+            .unwrap();
+
+        let result = sorter.sort_by_limit(input, |a, b| a.0.cmp(&b.0), limit).unwrap();
+        assert_eq!(result.is_in_memory(), in_memory);
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter(input_sorted.take(limit));
+
+        assert_eq!(actual_result, expected_result)
+    }
 
     #[rstest]
     #[case(false)]
     #[case(true)]
-    fn test_external_sorter(#[case] reversed: bool) {
+    fn test_external_sorter_limit_spilled(#[case] shuffled: bool) {
+        // every item takes 9 bytes when serialized
+        let base = 1u64 << 40;
+        let input_sorted = Vec::from_iter((0..10_000).map(|x| base + x));
+        let mut input = input_sorted.clone();
+        if shuffled {
+            input.shuffle(&mut rand::thread_rng());
+        }
+
+        let sorter: ExternalSorter<u64, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(100, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .build()
+            .unwrap();
+
+        let result = sorter
+            .sort_by_limit(input.into_iter().map(Ok), |a, b| a.cmp(b), 250)
+            .unwrap();
+        let stats = result.stats();
+
+        let actual_result: Result<Vec<u64>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), input_sorted[..250]);
+
+        // the dumped items are reduced to the first 250 ones once there are 500 of them
+        let items_spilled = stats.bytes_spilled / 9;
+        match shuffled {
+            false => assert_eq!((stats.chunks_written, items_spilled), (7, 1000)),
+            true => assert!(items_spilled < 4000, "{} items spilled", items_spilled),
+        }
+    }
+
+    #[test]
+    fn test_external_sorter_limit_near_buffer_size() {
+        let sorter: ExternalSorter<u64, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(100, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .build()
+            .unwrap();
+
+        // every input item gets into the top items selected so far
+        let input = (0..10_000).rev().map(Ok);
+        let compared = AtomicUsize::new(0);
+        let compare = |a: &u64, b: &u64| {
+            compared.fetch_add(1, atomic::Ordering::Relaxed);
+            return a.cmp(b);
+        };
+        let result = sorter.sort_by_limit(input, compare, 99).unwrap();
+
+        let actual_result: Result<Vec<u64>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), Vec::from_iter(0..99));
+
+        // the buffer is sorted once per its size of new items, not once per item
+        let compared = compared.load(atomic::Ordering::Relaxed);
+        assert!(compared < 10 * 10_000, "{} comparisons", compared);
+    }
+
+    #[rstest]
+    #[case(17 * 1024, None, true)]
+    #[case(64 * 1024, None, true)]
+    #[case(64 * 1024, Some(1), true)]
+    #[case(1024 * 1024, None, false)]
+    fn test_external_sorter_memory_budget(
+        #[case] budget: u64,
+        #[case] pipeline_depth: Option<usize>,
+        #[case] fanin_capped: bool,
+    ) {
+        let input_sorted = (0..50).flat_map(|x| (0..4).map(move |y| (x, y)));
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // sort input by the second field to check sorting stability
+        input_shuffled.sort_by_key(|a: &(i32, i32)| a.1);
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_memory_budget(budget);
+        if let Some(depth) = pipeline_depth {
+            builder = builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<(i32, i32), _> = builder.build().unwrap();
+
+        let result = sorter.sort_by(input, |a, b| a.0.cmp(&b.0)).unwrap();
+        // 28 chunks are dumped, a budget below the minimal read buffers of all of them caps the merge fan-in
+        assert_eq!(result.stats().intermediate_merges > 0, fanin_capped);
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter(input_sorted);
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[test]
+    fn test_external_sorter_memory_budget_split() {
+        let budget = 64 * 1024;
+        let sorter: ExternalSorter<String, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(10, false))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_rw_buf_size(1024)
+            .with_memory_budget(budget)
+            .build()
+            .unwrap();
+
+        let items = Vec::from_iter((0..10).map(|_| "x".repeat(100)));
+        let chunks = vec![(0, sorter.build_chunk(&sorter.new_context(), items).unwrap())];
+
+        // an item owns the heap memory taken by its 100 characters, serialized with a 2 bytes header
+        let item_memory = ExternalSorter::<String, io::Error>::item_memory(&chunks);
+        assert_eq!(item_memory, mem::size_of::<String>() as u64 + 102);
+
+        // the count limited buffer with its stable sorting scratch space and the write buffers of the buffer
+        // and of the intermediate merge
+        let merge_memory = budget - 10 * (item_memory + mem::size_of::<String>() as u64) - 2 * 1024;
+        assert_eq!(sorter.merge_memory(item_memory), Some(merge_memory));
+
+        // the fan-in is capped so that every chunk gets at least a minimal read buffer
+        let fanin = sorter.merge_fanin(item_memory).unwrap();
+        assert_eq!(fanin as u64, merge_memory / (MIN_READ_BUF_SIZE as u64 + item_memory));
+
+        // the memory is split across the read buffers of the chunks merged at once
+        let read_buf_size = sorter.read_buf_size(4, item_memory).unwrap() as u64;
+        assert_eq!(read_buf_size, merge_memory / 4 - item_memory);
+        assert_eq!(sorter.read_buf_size(fanin * 2, item_memory), Some(MIN_READ_BUF_SIZE));
+    }
+
+    #[rstest]
+    #[case(1000, false, false)]
+    #[case(1000, true, true)]
+    #[case(usize::MAX, true, false)]
+    fn test_external_sorter_memory_budget_exceeded(
+        #[case] buffer_limit: usize,
+        #[case] unstable: bool,
+        #[case] accepted: bool,
+    ) {
+        // a buffer of 1000 8 bytes items, its stable sorting scratch space takes as much again,
+        // and the write buffers of the buffer and of the intermediate merge
+        let budget = 1000 * 8 + 2 * 1024;
+        let result = ExternalSorterBuilder::<u64, io::Error>::new()
+            .with_buffer(LimitedBufferBuilder::new(buffer_limit, false))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_rw_buf_size(1024)
+            .with_unstable_sort(unstable)
+            .with_memory_budget(budget)
+            .build();
+
+        match result {
+            Ok(_) => assert!(accepted),
+            Err(SortError::InvalidConfig(_)) => assert!(!accepted),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[rstest]
+    #[case(1, None)]
+    #[case(3, None)]
+    #[case(2, Some(2))]
+    fn test_external_sorter_pipelined(#[case] depth: usize, #[case] fanin: Option<usize>) {
+        let input_sorted = (0..50).flat_map(|x| (0..4).map(move |y| (x, y)));
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // sort input by the second field to check sorting stability
+        input_shuffled.sort_by_key(|a: &(i32, i32)| a.1);
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let mut sorter_builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_pipeline_depth(depth);
+        if let Some(fanin) = fanin {
+            sorter_builder = sorter_builder.with_max_merge_fanin(fanin);
+        }
+        let sorter: ExternalSorter<(i32, i32), _> = sorter_builder.build().unwrap();
+
+        let result = sorter.sort_by(input, |a, b| a.0.cmp(&b.0)).unwrap();
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter(input_sorted);
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(1, 0)]
+    #[case(4, 2)]
+    fn test_external_sorter_prefetch(#[case] batch_size: usize, #[case] queue_size: usize) {
         let input_sorted = 0..100;
 
         let mut input_shuffled = Vec::from_iter(input_sorted.clone());
         input_shuffled.shuffle(&mut rand::thread_rng());
 
-        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(|item| Ok(item)));
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
 
         let sorter: ExternalSorter<i32, _> = ExternalSorterBuilder::new()
             .with_buffer(LimitedBufferBuilder::new(8, true))
             .with_threads_number(2)
             .with_tmp_dir(Path::new("./"))
+            .with_prefetch(batch_size, queue_size)
             .build()
             .unwrap();
 
-        let compare = if reversed {
-            |a: &i32, b: &i32| a.cmp(b).reverse()
-        } else {
-            |a: &i32, b: &i32| a.cmp(b)
-        };
-
-        let result = sorter.sort_by(input, compare).unwrap();
+        let result = sorter.sort(input).unwrap();
 
         let actual_result: Result<Vec<i32>, _> = result.collect();
         let actual_result = actual_result.unwrap();
-        let expected_result = if reversed {
-            Vec::from_iter(input_sorted.clone().rev())
-        } else {
-            Vec::from_iter(input_sorted.clone())
-        };
+        let expected_result = Vec::from_iter(input_sorted);
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(1, None, None)]
+    #[case(3, None, None)]
+    #[case(8, None, None)]
+    #[case(3, Some(6), None)]
+    #[case(3, None, Some(2))]
+    fn test_external_sorter_partitioned(
+        #[case] partitions: usize,
+        #[case] fanin: Option<usize>,
+        #[case] pipeline_depth: Option<usize>,
+    ) {
+        let input_sorted = (0..50).flat_map(|x| (0..4).map(move |y| (x, y)));
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+        // sort input by the second field to check sorting stability
+        input_shuffled.sort_by_key(|a: &(i32, i32)| a.1);
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let mut builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(30, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"));
+        if let Some(fanin) = fanin {
+            builder = builder.with_max_merge_fanin(fanin);
+        }
+        if let Some(depth) = pipeline_depth {
+            builder = builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<(i32, i32), _> = builder.build().unwrap();
+
+        let ranges = sorter
+            .sort_by_partitioned(input, |a, b| a.0.cmp(&b.0), partitions)
+            .unwrap();
+        assert_eq!(ranges.len(), partitions);
+
+        if let Some(fanin) = fanin {
+            // 6 chunks are dumped, the last buffer is kept in memory
+            let stats = ranges[0].stats();
+            assert!(stats.intermediate_merges > 0);
+            // every range merges at most the merge fan-in chunks
+            let range_chunks = stats.chunks_written - 6 - stats.intermediate_merges;
+            assert!(range_chunks as usize <= fanin * partitions);
+        }
+
+        let actual_ranges: Vec<Vec<(i32, i32)>> = std::thread::scope(|scope| {
+            let handles = Vec::from_iter(
+                ranges
+                    .into_iter()
+                    .map(|range| scope.spawn(|| range.collect::<Result<Vec<_>, _>>())),
+            );
+            Vec::from_iter(handles.into_iter().map(|handle| handle.join().unwrap().unwrap()))
+        });
+
+        for (lower, upper) in actual_ranges.iter().zip(actual_ranges.iter().skip(1)) {
+            if let (Some(lower), Some(upper)) = (lower.last(), upper.first()) {
+                assert!(lower.0 < upper.0);
+            }
+        }
+
+        let actual_result = Vec::from_iter(actual_ranges.into_iter().flatten());
+        let expected_result = Vec::from_iter(input_sorted);
 
         assert_eq!(actual_result, expected_result)
     }
@@ -382,38 +4326,194 @@ mod test {
     #[rstest]
     #[case(false)]
     #[case(true)]
-    fn test_external_sorter_stability(#[case] reversed: bool) {
-        let input_sorted = (0..20).flat_map(|x|(0..5).map(move |y| (x, y)));
+    fn test_external_sorter_partitioned_balanced(#[case] reversed: bool) {
+        let mut input_sorted = Vec::from_iter(0..1000);
+        if reversed {
+            input_sorted.reverse();
+        }
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_sorted.into_iter().map(Ok));
+
+        let sorter: ExternalSorter<i32, _> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(100, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .build()
+            .unwrap();
+
+        let ranges = sorter.sort_by_partitioned(input, i32::cmp, 4).unwrap();
+        let actual_ranges = Vec::from_iter(ranges.into_iter().map(|range| range.map(Result::unwrap).count()));
+
+        // the splitters are picked from the samples of all the chunks
+        assert_eq!(actual_ranges.iter().sum::<usize>(), 1000);
+        for range in actual_ranges {
+            assert!((200..=300).contains(&range), "unbalanced range of {} items", range);
+        }
+    }
+
+    #[rstest]
+    #[case(false, None)]
+    #[case(true, None)]
+    #[case(false, Some(2))]
+    fn test_external_sorter_replacement_selection(#[case] presorted: bool, #[case] fanin: Option<usize>) {
+        let input_sorted = (0..50).flat_map(|x| (0..4).map(move |y| (x, y)));
 
         let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        if !presorted {
+            input_shuffled.shuffle(&mut rand::thread_rng());
+            // sort input by the second field to check sorting stability
+            input_shuffled.sort_by_key(|a: &(i32, i32)| a.1);
+        }
+
+        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let mut sorter_builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(7, true))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_run_generation(RunGeneration::ReplacementSelection);
+        if let Some(fanin) = fanin {
+            sorter_builder = sorter_builder.with_max_merge_fanin(fanin);
+        }
+        let sorter: ExternalSorter<(i32, i32), _> = sorter_builder.build().unwrap();
+
+        let result = sorter.sort_by(input, |a, b| a.0.cmp(&b.0)).unwrap();
+
+        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter(input_sorted);
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(0..0, 0..200, 0..0)]
+    #[case(0..100, 100..200, 0..0)]
+    #[case(0..0, 0..100, 100..200)]
+    #[case(0..3, 3..20, 20..25)]
+    fn test_external_sorter_natural_runs(
+        #[case] ordered_head: std::ops::Range<i32>,
+        #[case] shuffled: std::ops::Range<i32>,
+        #[case] ordered_tail: std::ops::Range<i32>,
+    ) {
+        let mut input_shuffled = Vec::from_iter(shuffled.clone());
         input_shuffled.shuffle(&mut rand::thread_rng());
-        // sort input by the second field to check sorting stability
-        input_shuffled.sort_by(|a: &(i32, i32), b: &(i32, i32)| if reversed {a.1.cmp(&b.1).reverse()} else {a.1.cmp(&b.1)});
 
-        let input: Vec<Result<(i32, i32), io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(|item| Ok(item)));
+        let input_ordered = Vec::from_iter(ordered_head.clone().chain(shuffled.clone()).chain(ordered_tail.clone()));
+        let input_shuffled = Vec::from_iter(ordered_head.chain(input_shuffled).chain(ordered_tail));
 
-        let sorter: ExternalSorter<(i32, i32), _> = ExternalSorterBuilder::new()
+        for input in [input_ordered, input_shuffled] {
+            // the second field is used to check sorting stability
+            let input = Vec::from_iter(input.into_iter().map(|x| (x / 2, x)));
+            let mut expected_result = input.clone();
+            expected_result.sort_by_key(|a| a.0);
+
+            let sorter: ExternalSorter<(i32, i32), _> = ExternalSorterBuilder::new()
+                .with_buffer(LimitedBufferBuilder::new(8, true))
+                .with_threads_number(2)
+                .with_tmp_dir(Path::new("./"))
+                .with_run_generation(RunGeneration::NaturalRuns)
+                .build()
+                .unwrap();
+
+            let result = sorter
+                .sort_by(input.into_iter().map(Ok::<_, io::Error>), |a, b| a.0.cmp(&b.0))
+                .unwrap();
+
+            let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+            let actual_result = actual_result.unwrap();
+
+            assert_eq!(actual_result, expected_result)
+        }
+    }
+
+    #[test]
+    fn test_external_sorter_natural_runs_unaligned() {
+        // ascending runs longer than the buffer which do not start at buffer boundaries
+        let head = vec![500, 300, 400];
+        let runs = (0..17).rev().flat_map(|run| run * 12..(run + 1) * 12);
+        let input = Vec::from_iter(head.into_iter().chain(runs));
+        let mut expected_result = input.clone();
+        expected_result.sort();
+
+        let sorter: ExternalSorter<i32, _> = ExternalSorterBuilder::new()
             .with_buffer(LimitedBufferBuilder::new(8, true))
             .with_threads_number(2)
             .with_tmp_dir(Path::new("./"))
+            .with_run_generation(RunGeneration::NaturalRuns)
             .build()
             .unwrap();
 
-        let compare = if reversed {
-            |a: &(i32, i32), b: &(i32, i32)| a.0.cmp(&b.0).reverse()
-        } else {
-            |a: &(i32, i32), b: &(i32, i32)| a.0.cmp(&b.0)
-        };
+        let result = sorter.sort(input.into_iter().map(Ok::<_, io::Error>)).unwrap();
+        // the head items are dumped separately and every run gets its own chunk
+        assert_eq!(result.stats().chunks_written, 18);
 
-        let result = sorter.sort_by(input, compare).unwrap();
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        assert_eq!(actual_result.unwrap(), expected_result)
+    }
 
-        let actual_result: Result<Vec<(i32, i32)>, _> = result.collect();
+    #[rstest]
+    #[case(RunGeneration::SortBuffer, None)]
+    #[case(RunGeneration::SortBuffer, Some(2))]
+    #[case(RunGeneration::ReplacementSelection, None)]
+    #[case(RunGeneration::NaturalRuns, None)]
+    fn test_external_sorter_in_memory(#[case] run_generation: RunGeneration, #[case] pipeline_depth: Option<usize>) {
+        let input_sorted = 0..100;
+
+        let mut input_shuffled = Vec::from_iter(input_sorted.clone());
+        input_shuffled.shuffle(&mut rand::thread_rng());
+
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_shuffled.into_iter().map(Ok));
+
+        let mut sorter_builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(1000, false))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_run_generation(run_generation);
+        if let Some(depth) = pipeline_depth {
+            sorter_builder = sorter_builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<i32, _> = sorter_builder.build().unwrap();
+
+        let result = sorter.sort(input).unwrap();
+        assert!(result.is_in_memory());
+
+        let actual_result: Result<Vec<i32>, _> = result.collect();
         let actual_result = actual_result.unwrap();
-        let expected_result = if reversed {
-            Vec::from_iter(input_sorted.clone().rev())
-        } else {
-            Vec::from_iter(input_sorted.clone())
-        };
+        let expected_result = Vec::from_iter(input_sorted);
+
+        assert_eq!(actual_result, expected_result)
+    }
+
+    #[rstest]
+    #[case(RunGeneration::SortBuffer, None)]
+    #[case(RunGeneration::SortBuffer, Some(2))]
+    #[case(RunGeneration::NaturalRuns, None)]
+    fn test_external_sorter_last_chunk_in_memory(
+        #[case] run_generation: RunGeneration,
+        #[case] pipeline_depth: Option<usize>,
+    ) {
+        let input_sorted = 0..25;
+
+        // reversed input has no natural runs to be streamed
+        let input: Vec<Result<i32, io::Error>> = Vec::from_iter(input_sorted.clone().rev().map(Ok));
+
+        let mut sorter_builder = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(10, false))
+            .with_threads_number(2)
+            .with_tmp_dir(Path::new("./"))
+            .with_run_generation(run_generation);
+        if let Some(depth) = pipeline_depth {
+            sorter_builder = sorter_builder.with_pipeline_depth(depth);
+        }
+        let sorter: ExternalSorter<i32, _> = sorter_builder.build().unwrap();
+
+        let result = sorter.sort(input).unwrap();
+        // two full buffers are dumped, the last 5 items are merged right from memory
+        assert_eq!(result.stats().chunks_written, 2);
+
+        let actual_result: Result<Vec<i32>, _> = result.collect();
+        let actual_result = actual_result.unwrap();
+        let expected_result = Vec::from_iter(input_sorted);
 
         assert_eq!(actual_result, expected_result)
     }
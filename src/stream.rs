@@ -0,0 +1,350 @@
+//! Asynchronous sorting front end.
+
+use std::cmp::Ordering;
+use std::error::Error;
+use std::mem;
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::vec;
+
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::sort::{catch_panic, panic_message};
+use crate::{
+    ChunkBuffer, ChunkBufferBuilder, ExternalChunk, ExternalSorter, LimitedBufferBuilder, RmpExternalChunk, SortError,
+};
+
+/// Number of sorted items sent to the output stream at once.
+const OUTPUT_BATCH_SIZE: usize = 1024;
+/// Maximum number of sorted item batches merged in advance.
+const OUTPUT_QUEUE_SIZE: usize = 2;
+
+/// Asynchronous external sorter. It sorts a [`Stream`] of items and returns a [`Stream`] of sorted items
+/// without blocking the async runtime: filled chunk buffers are sorted on the sorter thread pool
+/// while chunk dumping and merging run on the tokio blocking threads.
+///
+/// Input reading overlaps with dumping of the previously filled buffer.
+/// Only [`RunGeneration::SortBuffer`](crate::RunGeneration::SortBuffer) run generation strategy is used,
+/// the sorter pipelining settings are not applied.
+pub struct AsyncExternalSorter<T, E, B = LimitedBufferBuilder, C = RmpExternalChunk<T>>
+where
+    T: Send,
+    E: Error,
+    B: ChunkBufferBuilder<T>,
+    C: ExternalChunk<T>,
+{
+    sorter: Arc<ExternalSorter<T, E, B, C>>,
+}
+
+impl<T, E, B, C> AsyncExternalSorter<T, E, B, C>
+where
+    T: Send + Sync + 'static,
+    E: Error + Send + Sync + 'static,
+    B: ChunkBufferBuilder<T> + Send + Sync + 'static,
+    B::Buffer: Send + 'static,
+    C: ExternalChunk<T> + Send + Sync + 'static,
+    C::SerializationError: Send + 'static,
+    C::DeserializationError: Send + 'static,
+{
+    /// Creates a new asynchronous sorter.
+    ///
+    /// # Arguments
+    /// * `sorter` - External sorter to be used to sort data
+    pub fn new(sorter: ExternalSorter<T, E, B, C>) -> Self {
+        AsyncExternalSorter {
+            sorter: Arc::new(sorter),
+        }
+    }
+
+    /// Sorts data from the input stream.
+    /// Returns a stream that can be used to get sorted data.
+    ///
+    /// # Arguments
+    /// * `input` - Input stream data to be fetched from
+    pub async fn sort<S>(
+        &self,
+        input: S,
+    ) -> Result<
+        SortedStream<T, SortError<C::SerializationError, C::DeserializationError, E>>,
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        T: Ord,
+        S: Stream<Item = Result<T, E>>,
+    {
+        self.sort_by(input, T::cmp).await
+    }
+
+    /// Sorts data from the input stream using a custom compare function.
+    /// Returns a stream that can be used to get sorted data.
+    ///
+    /// # Arguments
+    /// * `input` - Input stream data to be fetched from
+    /// * `compare` - Function be be used to compare items
+    pub async fn sort_by<S, F>(
+        &self,
+        input: S,
+        compare: F,
+    ) -> Result<
+        SortedStream<T, SortError<C::SerializationError, C::DeserializationError, E>>,
+        SortError<C::SerializationError, C::DeserializationError, E>,
+    >
+    where
+        S: Stream<Item = Result<T, E>>,
+        F: Fn(&T, &T) -> Ordering + Sync + Send + 'static,
+    {
+        self.sorter.check_not_persistent("async sorting")?;
+        let ctx = self.sorter.new_context();
+        let compare = Arc::new(compare);
+        let mut input = pin!(input);
+        let mut buffer = self.sorter.buffer_builder.build();
+        let mut chunks = Vec::new();
+        // dumping of the previously filled buffer
+        let mut dumping = None;
+
+        while let Some(item) = input.next().await {
+            self.sorter.check_cancelled(&ctx)?;
+            ctx.progress.on_item_read();
+            buffer.push(item.map_err(SortError::InputError)?);
+
+            if buffer.is_full() {
+                if let Some(dumping) = dumping.take() {
+                    chunks = join(dumping).await?;
+                }
+
+                let buffer = mem::replace(&mut buffer, self.sorter.buffer_builder.build());
+                let mut chunks = mem::take(&mut chunks);
+                let (sorter, compare, ctx) = (Arc::clone(&self.sorter), Arc::clone(&compare), ctx.clone());
+                dumping = Some(task::spawn_blocking(move || {
+                    catch_panic(|| {
+                        let chunk = sorter.create_chunk(&ctx, buffer, &*compare)?;
+                        sorter.push_chunk(&ctx, &mut chunks, chunk, &*compare)?;
+                        Ok(chunks)
+                    })
+                }));
+            }
+        }
+
+        if let Some(dumping) = dumping {
+            chunks = join(dumping).await?;
+        }
+
+        let sorter = Arc::clone(&self.sorter);
+        let merging = task::spawn_blocking(move || {
+            catch_panic(|| {
+                let memory_chunk = (!buffer.is_empty()).then(|| sorter.sort_in_memory(&ctx, buffer, &*compare));
+                sorter.merge_chunks(&ctx, chunks, memory_chunk, move |a: &T, b: &T| compare(a, b))
+            })
+        });
+        let sorted = join(merging).await?;
+
+        let (sender, receiver) = mpsc::channel(OUTPUT_QUEUE_SIZE);
+        task::spawn_blocking(move || send_batches(sorted, sender));
+
+        return Ok(SortedStream {
+            receiver,
+            batch: Vec::new().into_iter(),
+        });
+    }
+}
+
+impl<T, E, B, C> From<ExternalSorter<T, E, B, C>> for AsyncExternalSorter<T, E, B, C>
+where
+    T: Send + Sync + 'static,
+    E: Error + Send + Sync + 'static,
+    B: ChunkBufferBuilder<T> + Send + Sync + 'static,
+    B::Buffer: Send + 'static,
+    C: ExternalChunk<T> + Send + Sync + 'static,
+    C::SerializationError: Send + 'static,
+    C::DeserializationError: Send + 'static,
+{
+    fn from(sorter: ExternalSorter<T, E, B, C>) -> Self {
+        AsyncExternalSorter::new(sorter)
+    }
+}
+
+/// Stream of sorted items returned by [`AsyncExternalSorter`].
+/// Items are merged in a blocking thread in batches which are stored in a bounded queue.
+pub struct SortedStream<T, D> {
+    receiver: mpsc::Receiver<Vec<Result<T, D>>>,
+    batch: vec::IntoIter<Result<T, D>>,
+}
+
+// the stream fields are never pinned
+impl<T, D> Unpin for SortedStream<T, D> {}
+
+impl<T, D> Stream for SortedStream<T, D> {
+    type Item = Result<T, D>;
+
+    /// Returns the next item in the order defined by the compare function.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(item) = this.batch.next() {
+                return Poll::Ready(Some(item));
+            }
+            // the sender is dropped when the sorted items are read completely
+            match ready!(this.receiver.poll_recv(cx)) {
+                Some(batch) => this.batch = batch.into_iter(),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Awaits a blocking sorting job. A job that is not started before the runtime shuts down is cancelled
+/// which is returned as [`SortError::Cancelled`]. Panics are caught by the job itself, a panic escaping it
+/// anyway is returned as [`SortError::Panicked`].
+async fn join<R, S, D, I>(job: task::JoinHandle<Result<R, SortError<S, D, I>>>) -> Result<R, SortError<S, D, I>>
+where
+    S: Error,
+    D: Error,
+    I: Error,
+{
+    return match job.await {
+        Ok(result) => result,
+        Err(err) if err.is_cancelled() => Err(SortError::Cancelled),
+        Err(err) => Err(SortError::Panicked(panic_message(err.into_panic()))),
+    };
+}
+
+/// Sends the sorted items to the stream in batches until the items are exhausted, broken
+/// or the stream is dropped.
+fn send_batches<T, D>(items: impl Iterator<Item = Result<T, D>>, sender: mpsc::Sender<Vec<Result<T, D>>>) {
+    let mut items = items;
+    loop {
+        let mut batch = Vec::with_capacity(OUTPUT_BATCH_SIZE);
+        let mut failed = false;
+
+        for item in items.by_ref() {
+            failed = item.is_err();
+            batch.push(item);
+            if failed || batch.len() >= OUTPUT_BATCH_SIZE {
+                break;
+            }
+        }
+
+        if batch.is_empty() || sender.blocking_send(batch).is_err() || failed {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::atomic::{self, AtomicU64};
+    use std::sync::Arc;
+
+    use futures::{future, stream, StreamExt};
+    use rand::seq::SliceRandom;
+    use rstest::*;
+    use tokio::task;
+
+    use super::{join, AsyncExternalSorter};
+    use crate::{ExternalSorter, ExternalSorterBuilder, LimitedBufferBuilder, SortError};
+
+    #[fixture]
+    fn tmp_dir() -> tempfile::TempDir {
+        tempfile::tempdir().unwrap()
+    }
+
+    #[rstest]
+    #[case(8, 3000)]
+    #[case(5000, 3000)]
+    #[tokio::test]
+    async fn test_async_external_sorter(tmp_dir: tempfile::TempDir, #[case] buffer_size: usize, #[case] len: i32) {
+        let mut input_shuffled = Vec::from_iter(0..len);
+        input_shuffled.shuffle(&mut rand::thread_rng());
+
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(buffer_size, true))
+            .with_threads_number(2)
+            .with_tmp_dir(tmp_dir.path())
+            .with_max_merge_fanin(4)
+            .build()
+            .unwrap();
+        let sorter = AsyncExternalSorter::new(sorter);
+
+        // the test runtime is single threaded, so the ticker only runs while the sorting is waiting
+        // for the blocking jobs instead of blocking the runtime
+        let ticks = Arc::new(AtomicU64::new(0));
+        let ticker = task::spawn({
+            let ticks = Arc::clone(&ticks);
+            async move {
+                loop {
+                    ticks.fetch_add(1, atomic::Ordering::Relaxed);
+                    task::yield_now().await;
+                }
+            }
+        });
+
+        let input = stream::iter(input_shuffled.into_iter().map(Ok));
+        let result = sorter
+            .sort_by(input, |a: &i32, b: &i32| a.cmp(b).reverse())
+            .await
+            .unwrap();
+        assert!(ticks.load(atomic::Ordering::Relaxed) > 0);
+        ticker.abort();
+
+        // the sorted items are sent in several batches
+        let actual_result: Result<Vec<i32>, _> = result.collect::<Vec<_>>().await.into_iter().collect();
+        assert_eq!(actual_result.unwrap(), Vec::from_iter((0..len).rev()))
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_async_external_sorter_input_error(tmp_dir: tempfile::TempDir) {
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(8, true))
+            .with_tmp_dir(tmp_dir.path())
+            .build()
+            .unwrap();
+        let sorter = AsyncExternalSorter::from(sorter);
+
+        let input = stream::iter((0..20).map(|item| match item {
+            15 => Err(io::Error::other("test error")),
+            item => Ok(item),
+        }));
+
+        match sorter.sort(input).await {
+            Err(err) => assert_eq!(err.to_string(), "input data stream error: test error"),
+            Ok(_) => panic!("input error expected"),
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_async_external_sorter_persistent(tmp_dir: tempfile::TempDir) {
+        let sorter: ExternalSorter<i32, io::Error> = ExternalSorterBuilder::new()
+            .with_buffer(LimitedBufferBuilder::new(8, true))
+            .with_tmp_dir(tmp_dir.path())
+            .with_persistence(&tmp_dir.path().join("persistent"), "asc")
+            .build()
+            .unwrap();
+        let sorter = AsyncExternalSorter::from(sorter);
+
+        match sorter.sort(stream::iter((0..20).map(Ok))).await {
+            Err(err) => assert!(matches!(err, SortError::InvalidConfig(_))),
+            Ok(_) => panic!("persistent mode is expected to be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join() {
+        type JobResult = Result<(), SortError<io::Error, io::Error, io::Error>>;
+
+        let cancelled = task::spawn(future::pending::<JobResult>());
+        cancelled.abort();
+        assert!(matches!(join(cancelled).await, Err(SortError::Cancelled)));
+
+        let panicked = task::spawn_blocking(|| -> JobResult { panic!("job failed") });
+        match join(panicked).await {
+            Err(SortError::Panicked(msg)) => assert_eq!(msg, "job failed"),
+            _ => panic!("panic is expected to be returned"),
+        }
+    }
+}